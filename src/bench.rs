@@ -1,36 +1,169 @@
+use bench_args::BenchArgs;
+use calibrate;
+use cache::{self, BuildConfig};
+use cachegrind::{self, Counts};
 use csv;
+use data::{self, Measurement};
+use doctor;
 use errors::*;
 use git;
-use git2::{ObjectType, Repository};
+use git2::{Commit, Object, ObjectType, Oid, Reference, Repository};
 use git2::build::CheckoutBuilder;
 use glob;
 use pbr::ProgressBar;
 use regex::Regex;
+use rusage;
+use sched::Sched;
+use std::cmp::{self, Ordering};
+use std::collections::HashMap;
 use std::env;
-use std::fs::OpenOptions;
-use std::path::Path;
-use std::process::Command;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::io::prelude::*;
 use std::str;
+use store::{self, Storage};
+use variant::Variant;
 
 lazy_static! {
     // Example:
     // test nbody::bench::nbody_par              ... bench:  12,459,703 ns/iter (+/- 75,027)
+    // test parse::bench::parse_large            ... bench:     181,052 ns/iter (+/- 2,114) = 552 MB/s
     pub static ref BENCH_RE: Regex = Regex::new(
-        r"\s*test\s+([^ ]+)\s*...\s*bench:\s*([0-9,]+) ns/iter \(\+/- ([0-9,]+)\)\s*").unwrap();
+        r"\s*test\s+([^ ]+)\s*...\s*bench:\s*([0-9,.]+) ns/iter \(\+/- ([0-9,.]+)\)(?:\s*=\s*([0-9,.]+) MB/s)?\s*").unwrap();
 }
 
-pub fn bench(data_file: &str,
-             ignore_dirty: &[String],
-             flag_repeat: usize,
-             commits: &Option<String>,
-             bench_options: &[String])
-             -> Result<()> {
+pub struct Config<'c> {
+    pub ignore_dirty: &'c [String],
+    pub repeat: &'c str, // a number, or `auto`
+    pub target_ci: &'c str, // for `--repeat auto`, e.g. `2%`
+    pub max_repeat: usize, // for `--repeat auto`
+    pub warmup: usize, // runs of each benchmark to discard, per commit
+    pub commits: &'c Option<String>,
+    pub bench_options: &'c [String],
+    pub variants: &'c [String],
+    pub sweep: &'c Option<String>,
+    pub shuffle: &'c Option<String>, // a seed, or `random`
+    pub cpu_list: &'c Option<String>, // e.g. `4-7`, for `taskset`
+    pub nice: &'c Option<String>,
+    pub dry_run: bool,
+    pub strict: bool, // refuse to run if the machine looks noisy
+
+    // for `build-bench`: only build each commit (even if cached),
+    // recording how long it took
+    pub build_only: bool,
+    pub clean: bool, // clean the workspace's packages before each build
+    pub sizes: bool, // record the size of the bench executables
+
+    // run each benchmark once under cachegrind, recording instruction
+    // and cache miss counts rather than time
+    pub cachegrind: bool,
+
+    // run the calibration workload before measuring each commit
+    pub calibrate: bool,
+
+    // stash any uncommitted changes while benchmarking, and reapply
+    // them afterwards
+    pub stash: bool,
+
+    pub storage: &'c str, // `csv`, `notes` or `dir`; see `store::Storage`
+
+    // measure the uncommitted changes too, as if committed on top of
+    // HEAD (implies `stash`)
+    pub include_worktree: bool,
+}
+
+/// Benchmarks are run `AUTO_MIN_REPEAT` times before checking if
+/// `--repeat auto` should take more samples.
+const AUTO_MIN_REPEAT: usize = 5;
+
+/// The metrics whose stability `--repeat auto` checks (the others,
+/// like context switches, are too noisy to converge).
+const STABLE_METRICS: &'static [&'static str] = &["time", "instructions"];
+
+/// How many times to run each benchmark.
+#[derive(Copy, Clone, Debug)]
+enum Repeat {
+    Fixed(usize),
+
+    // until the confidence interval of the median of each test is
+    // narrower than `target_ci` (relative to the median), up to `max` runs
+    Auto { target_ci: f64, max: usize },
+}
+
+impl Repeat {
+    fn parse(repeat: &str, target_ci: &str, max: usize) -> Result<Repeat> {
+        if repeat != "auto" {
+            return match repeat.parse() {
+                Ok(n) => Ok(Repeat::Fixed(n)),
+                Err(_) => throw!("`--repeat` should be a number or `auto`, not `{}`", repeat),
+            };
+        }
+        let percent: f64 = match target_ci.trim_end_matches('%').parse() {
+            Ok(p) if p > 0.0 => p,
+            _ => throw!("`--target-ci` should be a percentage, like `2%`, not `{}`", target_ci),
+        };
+        Ok(Repeat::Auto {
+            target_ci: percent / 100.0,
+            max: max,
+        })
+    }
+
+    /// How many runs to plan for each benchmark.
+    fn planned(&self) -> usize {
+        match *self {
+            Repeat::Fixed(n) => n,
+            Repeat::Auto { max, .. } => cmp::min(AUTO_MIN_REPEAT, max),
+        }
+    }
+}
+
+/// An environment variable to set to each of a list of values in
+/// turn, e.g. `RAYON_NUM_THREADS=1,2,4,8`.
+struct Sweep {
+    name: String,
+    values: Vec<String>,
+}
+
+impl Sweep {
+    fn parse(spec: &str) -> Result<Sweep> {
+        let i = match spec.find('=') {
+            Some(i) => i,
+            None => throw!("sweep `{}` should look like `VAR=value1,value2,...`", spec),
+        };
+        let values: Vec<_> = spec[i + 1..]
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if values.is_empty() {
+            throw!("sweep `{}` has no values", spec);
+        }
+        Ok(Sweep {
+            name: spec[..i].to_string(),
+            values: values,
+        })
+    }
+}
+
+/// Run the benchmarks as set up in `config`, appending the results to
+/// `data_file`. Returns the id of the run (unless it was a dry run).
+pub fn bench(data_file: &str, config: Config) -> Result<Option<String>> {
     let data_path: &Path = Path::new(data_file);
+    if config.clean && !config.build_only {
+        throw!("`--clean` only applies to `build-bench`");
+    }
 
     // Find the files that match the ignore patterns.
-    let mut ignored_paths = vec![data_path.to_owned()];
-    for pattern in ignore_dirty {
+    let runs_path = data::runs_path(data_path);
+    let data_paths = vec![data_path.to_owned(),
+                          runs_path.clone(),
+                          data::runs_dir(data_path),
+                          data::noise_path(data_path)];
+    let mut ignored_paths = data_paths.clone();
+    for pattern in config.ignore_dirty {
         let paths = glob::glob(pattern).chain_err(|| format!("invalid glob pattern: `{}`", pattern))?;
         for path in paths {
             let path = path.chain_err(|| format!("error accessing path for pattern `{}`", pattern))?;
@@ -38,172 +171,1431 @@ pub fn bench(data_file: &str,
         }
     }
 
-    // Open the data file for append early, so that we detect errors
-    // *before* we run cargo bench.
-    let data_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&data_path)
-        .chain_err(|| format!("failed to open data file `{}`", data_path.display()))?;
-
-    // Find the current commit. Check that repository is clean.
     let current_dir = env::current_dir().chain_err(|| "failed to find current dir")?;
     let repo = git::open_repo(&current_dir).chain_err(|| "failed to open git repo")?;
-    git::check_clean(&repo, &ignored_paths)?;
     let head = repo.head().chain_err(|| "failed to fetch HEAD from repo")?;
 
-    // Parse the `bench_options` and separate them into benchmark names (no leading `-`)
-    // and flags.
-    let (bench_flags, mut bench_names): (Vec<_>, Vec<_>) = bench_options.iter()
-        .cloned()
-        .partition(|s| s.starts_with("-"));
-
+    // Parse the `bench_options` and separate them into cargo flags,
+    // benchmark names and harness arguments.
+    let args = BenchArgs::parse(config.bench_options)?;
+    let mut bench_names = args.filters.clone();
     if bench_names.is_empty() {
         bench_names.push(String::new());
     }
 
-    let runs_per_commit = flag_repeat * bench_names.len() + 1;
+    // If the user gave us a list of commits, we will check out each
+    // one in turn; otherwise, just measure HEAD.
+    let head_commit = head.peel(ObjectType::Commit)
+        .chain_err(|| "HEAD not a commit")?;
+    let mut names: Vec<_> = if let Some(ref commits_str) = *config.commits {
+        // let users write "a,b" or "a b"
+        commits_str.split(",").flat_map(|s| s.split_whitespace()).collect()
+    } else {
+        vec!["HEAD"]
+    };
+    if config.include_worktree && !names.contains(&git::WORKTREE) {
+        names.push(git::WORKTREE);
+    }
+
+    // The uncommitted changes are measured as a commit of their own,
+    // on top of HEAD. (A dry run writes nothing to the repository: its
+    // commit goes to an object database in memory, which is dropped
+    // with `odb`.)
+    let odb = repo.odb().chain_err(|| "failed to open the object database")?;
+    if config.dry_run {
+        odb.add_new_mempack_backend(1000)
+            .chain_err(|| "failed to create an object database in memory")?;
+    }
+    let worktree = if names.contains(&git::WORKTREE) {
+        let dirty_paths: Vec<_> = ignored_paths.iter().filter(|p| p.exists()).cloned().collect();
+        let head_commit = head_commit.peel_to_commit().chain_err(|| "HEAD not a commit")?;
+        let worktree = git::worktree_commit(&repo, &head_commit, &dirty_paths)?;
+        if worktree.is_none() {
+            println_err!("warning: there are no uncommitted changes to measure");
+        }
+        worktree.map(|c| c.into_object())
+    } else {
+        None
+    };
+    let revisions: Vec<_> = try!(names.iter()
+        .filter_map(|&c| if c == git::WORKTREE {
+            worktree.clone().map(Ok)
+        } else {
+            Some(repo.revparse_single(c).chain_err(|| format!("invalid revision '{}'", c)))
+        })
+        .collect());
+    if let Some(r) = revisions.iter().find(|r| r.as_commit().is_none()) {
+        bail!("revision `{}` is not a commit", git::short_id(r));
+    }
+    let checkout = config.commits.is_some() || worktree.is_some();
+
+    // Each commit is built and measured once per variant.
+    let variants: Vec<_> = if config.variants.is_empty() {
+        vec![Variant::default()]
+    } else {
+        try!(config.variants.iter().map(|v| Variant::parse(v)).collect())
+    };
+    let builds: Vec<_> = try!(variants.into_iter()
+        .map(|v| BuildConfig::new(&args.cargo_flags, &v).map(|b| (v, b)))
+        .collect());
+
+    let cache_dir = cache::cache_dir(&args.cargo_flags)?;
+
+    let sweep = match *config.sweep {
+        Some(ref spec) => Some(Sweep::parse(spec)?),
+        None => None,
+    };
+
+    let sched = Sched::parse(config.cpu_list, config.nice)?;
+    let storage = Storage::parse(config.storage)?;
+    let seed = shuffle_seed(config.shuffle)?;
+    let repeat = Repeat::parse(config.repeat, config.target_ci, config.max_repeat)?;
+    let runs = if config.build_only {
+        vec![]
+    } else {
+        plan_runs(&bench_names,
+                  &args.harness_args,
+                  sweep.as_ref(),
+                  &sched,
+                  repeat.planned())
+    };
+
+    // Shuffling only changes something if there are different runs to
+    // put in another order. (Without filters, each run at a commit runs
+    // every benchmark, so they would all be the same.)
+    let mut groups: Vec<Group> = runs.iter().map(|r| (r.bench_name, r.param)).collect();
+    groups.dedup();
+    if seed.is_some() && groups.len() == 1 {
+        throw!("`--shuffle` needs benchmarks to shuffle: name them as filters (or give a \
+                `--sweep`)");
+    }
+
+    // Without `--commits`, HEAD is built as it is in the working tree,
+    // which keeps any changes to files that `--ignore-dirty` matches
+    // (even with `--stash`). Such a build is not HEAD's, so it is neither
+    // taken from the cache nor kept there. (The data files may not exist
+    // yet.)
+    ignored_paths.retain(|p| p.exists());
+    let dirty_build = if checkout {
+        false
+    } else {
+        let data_paths: Vec<_> = data_paths.into_iter().filter(|p| p.exists()).collect();
+        let dirty = git::dirty_files(&repo, &data_paths)?;
+        dirty.len() != git::dirty_files(&repo, &ignored_paths)?.len()
+    };
+
+    let plan = Plan {
+        config: &config,
+        args: &args,
+        repo: &repo,
+        data_path: data_path,
+        run_id: data::new_run_id(),
+        ignored_paths: ignored_paths,
+        revisions: revisions,
+        builds: builds,
+        runs: runs,
+        checkout: checkout,
+        cache_dir: cache_dir,
+        dirty_build: dirty_build,
+        sched: &sched,
+        seed: seed,
+        repeat: repeat,
+        storage: storage,
+    };
+    if config.dry_run {
+        return dry_run(&plan).map(|()| None);
+    }
+
+    // Open the data file for append early, so that we detect errors
+    // *before* we run cargo bench. (With `notes` storage, the results
+    // of the working tree go on the commit it is based on.)
+    let mut note_commits = HashMap::new();
+    for commit in plan.revisions.iter().filter_map(|r| r.as_commit()) {
+        let id = if git::is_worktree(commit) {
+            commit.parent_id(0).ok()
+        } else {
+            Some(commit.id())
+        };
+        note_commits.extend(id.map(|id| (git::label(commit), id)));
+    }
+    let mut output = store::Output::open(data_path, storage, &plan.run_id, &repo, note_commits)?;
+    let runs_file = if storage != Storage::Dir {
+        let file = store::Output::append(&runs_path).chain_err(|| {
+            format!("failed to open run metadata file `{}`", runs_path.display())
+        })?;
+        Some(file)
+    } else {
+        None // kept in the run file
+    };
+
+    // Stash away any changes (the data files aside), if asked to, and
+    // check that repository is clean.
+    let stash = if config.stash || worktree.is_some() {
+        git::stash(&repo, &plan.ignored_paths)?
+    } else {
+        None
+    };
+    if let Some(stash) = stash {
+        println_err!("stashed your changes as `{}`; they will be reapplied afterwards", stash);
+    }
+
+    // Whatever happens while measuring, HEAD and the stashed changes
+    // are restored afterwards. (A run file has rows of both metadata
+    // and measurements.)
+    let run_id = {
+        let mut writer = csv::Writer::from_writer(&mut output).flexible(true);
+        let measured = measure(&plan, &mut writer, runs_file, stash);
+
+        // The measurements of the last commit are stored here, as are
+        // those of a commit that ends in an error part way.
+        let stored = writer.flush().chain_err(|| "failed to write the measurements");
+        match (measured, stored) {
+            (Ok(run_id), Ok(())) => Ok(run_id),
+            (Err(e), Ok(())) => {
+                println_err!("note: anything measured before the error below is saved (run `{}`)",
+                             plan.run_id);
+                Err(e)
+            }
+            (Ok(_), Err(e)) => Err(e),
+            (Err(e), Err(stored)) => {
+                println_err!("error: anything measured before the error below is lost: {}", stored);
+                Err(e)
+            }
+        }
+    };
+    let finished = output.finish();
+    let run_id = run_id.and_then(|run_id| finished.map(|()| run_id));
+
+    let mut cleanup = Ok(());
+    if plan.checkout {
+        cleanup = restore_head(&repo, &head, &head_commit);
+    }
+    if let Some(stash) = stash {
+        // Applying the stash anywhere but the original HEAD could
+        // conflict, so it is left alone.
+        cleanup = match cleanup {
+            Ok(()) => git::unstash(&repo, stash),
+            Err(e) => {
+                Err(e).chain_err(|| {
+                    format!("your changes were not reapplied; they are kept in stash `{}` \
+                             (see `git stash list`)",
+                            stash)
+                })
+            }
+        };
+    }
+
+    match (run_id, cleanup) {
+        (Ok(run_id), Ok(())) => Ok(Some(run_id)),
+        (Err(e), Ok(())) | (Ok(_), Err(e)) => Err(e),
+        (Err(e), Err(cleanup)) => {
+            // Report both, or the user may not notice their changes
+            // are still stashed.
+            for (i, e) in cleanup.iter().enumerate() {
+                println_err!("{}: {}", if i == 0 { "error" } else { "caused by" }, e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Check that the working tree is clean and the machine quiet, record
+/// how the session was invoked (in `runs_file`, or `writer` if it is
+/// `None`), then build and measure each commit under each variant, as
+/// planned, writing the results to `writer`. Returns the id of the run.
+fn measure<W: Write>(plan: &Plan,
+                     writer: &mut csv::Writer<W>,
+                     runs_file: Option<store::Output>,
+                     stash: Option<Oid>)
+                     -> Result<String> {
+    let config = plan.config;
+    git::check_clean(plan.repo, &plan.ignored_paths)?;
+
+    // ...and that the machine is quiet.
+    let checks = doctor::machine_checks();
+    let problems: Vec<_> = checks.iter().filter_map(|c| c.problem.as_ref()).collect();
+    if config.strict && !problems.is_empty() {
+        throw!("refusing to benchmark (see `cargo-chrono doctor`): {}",
+               problems.iter().map(|p| &p[..]).collect::<Vec<_>>().join("; "));
+    }
+    for problem in &problems {
+        println_err!("warning: {}", problem);
+    }
+
+    // Record how this session was invoked.
+    let labels: Vec<_> = plan.revisions.iter()
+        .filter_map(|r| r.as_commit())
+        .map(git::label)
+        .collect();
+    let mut metadata = vec![("commits", labels.join(" ")),
+                            ("cargo_flags", plan.args.cargo_flags.join(" ")),
+                            ("filters", plan.args.filters.join(" ")),
+                            ("harness_args", plan.args.harness_args.join(" ")),
+                            ("repeat", config.repeat.to_string()),
+                            ("warmup", warmup_runs(config).to_string())];
+    if let Some(seed) = plan.seed {
+        metadata.push(("shuffle_seed", seed.to_string()));
+    }
+    if let Repeat::Auto { .. } = plan.repeat {
+        metadata.push(("target_ci", config.target_ci.to_string()));
+        metadata.push(("max_repeat", config.max_repeat.to_string()));
+    }
+    if let Some(ref spec) = *config.sweep {
+        metadata.push(("sweep", spec.clone()));
+    }
+    if !plan.sched.cpus.is_empty() {
+        metadata.push(("cpu_list", plan.sched.describe_cpus()));
+    }
+    if let Some(nice) = plan.sched.nice {
+        metadata.push(("nice", nice.to_string()));
+    }
+    if config.cachegrind {
+        metadata.push(("backend", "cachegrind".to_string()));
+    }
+    if config.calibrate {
+        metadata.push(("calibrate", "true".to_string()));
+    }
+    if let Some(stash) = stash {
+        metadata.push(("stash", stash.to_string()));
+    }
+    if plan.storage != Storage::Csv {
+        metadata.push(("storage", config.storage.to_string()));
+    }
+    if config.build_only {
+        metadata.push(("build_only", "true".to_string()));
+        metadata.push(("clean", config.clean.to_string()));
+    }
+    for spec in config.variants {
+        metadata.push(("variant", spec.clone()));
+    }
+    for check in &checks {
+        metadata.push((check.name, check.value.clone()));
+    }
+    for &(_, ref build) in &plan.builds {
+        metadata.push(("toolchain", build.version.clone()));
+    }
+    match runs_file {
+        Some(file) => data::write_run(&mut csv::Writer::from_writer(file), &plan.run_id, &metadata)?,
+        None => data::write_run(writer, &plan.run_id, &metadata)?,
+    }
 
-    let mut bar = ProgressBar::new(runs_per_commit as u64);
+    let warmups = plan.runs.iter().filter(|r| r.index == 0).count() * warmup_runs(config);
+    let calibrations = if config.calibrate { 1 } else { 0 };
+    let runs_per_commit = plan.runs.len() + warmups + calibrations + 1;
+
+    let mut bar = ProgressBar::new((runs_per_commit * plan.builds.len() * plan.revisions.len()) as u64);
     bar.show_speed = false;
     bar.show_counter = false;
     bar.show_time_left = false;
     bar.show_tick = false;
     bar.show_message = true;
 
-    // if the user gave us a list of commits, check out each one in turn
-    let mut writer = csv::Writer::from_writer(data_file);
-    if let Some(ref commits_str) = *commits {
-        // let users write "a,b" or "a b"
-        let head_commit = head.peel(ObjectType::Commit)
-            .chain_err(|| "HEAD not a commit")?;
-        let revisions: Vec<_> = try!(commits_str.split(",")
-            .flat_map(|s| s.split_whitespace())
-            .map(|c| repo.revparse_single(c).chain_err(|| format!("invalid revision '{}'", c)))
-            .collect());
-        if let Some(r) = revisions.iter().find(|r| r.as_commit().is_none()) {
-            bail!("revision `{}` is not a commit", git::short_id(r));
-        }
-        let total_commits = revisions.len();
-        bar.total *= total_commits as u64;
-        for commit in revisions.iter().filter_map(|r| r.as_commit()) {
-            bar.message(&format!("checking out `{}`", git::short_id(commit)));
-            git::checkout_commit(&repo, commit)
-                .chain_err(|| format!("failed to checkout commit `{}`", git::short_id(commit)))?;
-            run_bench(&mut bar,
-                      &repo,
-                      &mut writer,
-                      &bench_flags,
-                      &bench_names,
-                      flag_repeat)
-                ?;
-        }
-        bar.message("restoring HEAD");
-        repo.checkout_tree(&head_commit, Some(&mut CheckoutBuilder::new()))
-            .chain_err(|| {
-                format!("failed to checkout original HEAD `{}`",
-                        git::short_id(&head_commit))
-            })?;
-        let name = head.name().ok_or("HEAD not utf-8")?;
-        repo.set_head(name)
-            .chain_err(|| format!("failed to restore original HEAD `{}`", name))?;
+    let mut rng = plan.seed.map(Rng::new);
+
+    // digest (see `run_digest`) -> (commit, measurements), of the
+    // commits measured so far (in this session or before)
+    let mut measured = if config.build_only {
+        HashMap::new()
+    } else {
+        by_digest(load_stored(plan)?)
+    };
+    for commit in plan.revisions.iter().filter_map(|r| r.as_commit()) {
+        for build in &plan.builds {
+            // Store the measurements of the previous commit (or
+            // variant) before going on, in one locked append.
+            writer.flush().chain_err(|| "failed to write the measurements")?;
+
+            let variant = &build.0;
+            let (entry, build_time) = build_commit(&mut bar, plan, commit, &build.1)?;
+            let label = git::label(commit);
+
+            // Record how long the build took (if we did build), and
+            // how big the result is.
+            let variant_name = Some(variant.name.clone()).filter(|v| !v.is_empty());
+            if let Some(build_time) = build_time {
+                let test = if config.clean { "clean" } else { "incremental" };
+                data::write_measurement(writer,
+                                        &Measurement {
+                                            run: Some(plan.run_id.clone()),
+                                            variant: variant_name.clone(),
+                                            ..Measurement::new(&label,
+                                                               test,
+                                                               "build-time",
+                                                               build_time as f64)
+                                        })?;
+            }
+            if config.sizes {
+                let mut total = 0;
+                for executable in &entry.executables {
+                    let size = fs::metadata(&executable.path)
+                        .chain_err(|| {
+                            format!("failed to read `{}`", executable.path.display())
+                        })?
+                        .len();
+                    total += size;
+                    data::write_measurement(writer,
+                                            &Measurement {
+                                                run: Some(plan.run_id.clone()),
+                                                variant: variant_name.clone(),
+                                                package: Some(executable.package.clone())
+                                                    .filter(|p| !p.is_empty()),
+                                                target: Some(executable.name.clone()),
+                                                ..Measurement::new(&label,
+                                                                   &executable.name,
+                                                                   "binary-size",
+                                                                   size as f64)
+                                            })?;
+                }
+                data::write_measurement(writer,
+                                        &Measurement {
+                                            run: Some(plan.run_id.clone()),
+                                            variant: variant_name.clone(),
+                                            ..Measurement::new(&label,
+                                                               "total",
+                                                               "binary-size",
+                                                               total as f64)
+                                        })?;
+            }
+            if config.build_only {
+                continue;
+            }
+
+            // If an earlier commit built byte-identical executables (and
+            // they were run the same way), reuse its results rather than
+            // measuring again. (A commit measured before is measured
+            // again, though.)
+            let digest = run_digest(plan, variant, &build.1, &entry)?;
+            let earlier = measured.get(&digest).filter(|&&(ref original, _)| *original != label);
+            if let Some(&(ref original, ref measurements)) = earlier {
+                bar.message(&format!("`{}` is identical to `{}`", label, original));
+                bar.add(runs_per_commit as u64 - 1);
+                for m in measurements {
+                    data::write_measurement(writer,
+                                            &Measurement {
+                                                commit: label.clone(),
+                                                alias_of: Some(original.clone()),
+                                                run: Some(plan.run_id.clone()),
+                                                ..m.clone()
+                                            })?;
+                }
+                continue;
+            }
+
+            let mut commit_runs = plan.runs.clone();
+            if let Some(ref mut rng) = rng {
+                rng.shuffle(&mut commit_runs);
+            }
+            // what all the measurements of this commit have in common
+            let template = Measurement {
+                run: Some(plan.run_id.clone()),
+                variant: variant_name,
+                digest: Some(digest.clone()),
+                ..Measurement::new(&label, "", "time", 0.0)
+            };
+            let mut measurements = vec![];
+            if config.calibrate {
+                bar.message(&format!("calibrating before `{}`", label));
+                bar.inc();
+                let measurement = run_calibration(&template, plan.sched)?;
+                data::write_measurement(writer, &measurement)?;
+                measurements.push(measurement);
+            }
+            measurements.extend(run_bench(&mut bar,
+                                          writer,
+                                          plan,
+                                          &template,
+                                          build,
+                                          &entry,
+                                          &commit_runs)?);
+            measured.insert(digest, (label, measurements));
+        }
+    }
+    Ok(plan.run_id.clone())
+}
+
+/// Identifies the bench executables in `entry` and how they are run
+/// (under `build`, with the filters, repetitions and so forth of
+/// `plan`): commits with the same digest get the same results.
+fn run_digest(plan: &Plan,
+              variant: &Variant,
+              build: &BuildConfig,
+              entry: &cache::Entry)
+              -> Result<String> {
+    let config = plan.config;
+    let mut context = vec![("variant", variant.name.clone())];
+    for &(ref key, ref value) in &build.env {
+        context.push(("env", format!("{}={}", key, value)));
+    }
+    context.push(("filters", plan.args.filters.join(" ")));
+    context.push(("harness_args", plan.args.harness_args.join(" ")));
+    context.push(("sweep", config.sweep.clone().unwrap_or_default()));
+    context.push(("repeat", config.repeat.to_string()));
+    if let Repeat::Auto { .. } = plan.repeat {
+        context.push(("target_ci", config.target_ci.to_string()));
+        context.push(("max_repeat", config.max_repeat.to_string()));
+    }
+    context.push(("warmup", warmup_runs(config).to_string()));
+    context.push(("cachegrind", config.cachegrind.to_string()));
+    context.push(("calibrate", config.calibrate.to_string()));
+    context.push(("cpu_list", plan.sched.describe_cpus()));
+    context.push(("nice", plan.sched.nice.map(|n| n.to_string()).unwrap_or_default()));
+    let context: Vec<_> = context.into_iter()
+        .map(|(name, value)| (name.to_string(), value.into_bytes()))
+        .collect();
+    cache::digest(entry, &context)
+}
+
+/// The measurements stored so far (if any).
+fn load_stored(plan: &Plan) -> Result<Vec<Measurement>> {
+    let data_file = plan.data_path.to_string_lossy();
+    if store::exists(&data_file, plan.storage) {
+        store::load_measurements(&data_file, plan.storage)
     } else {
-        run_bench(&mut bar,
-                  &repo,
-                  &mut writer,
-                  &bench_flags,
-                  &bench_names,
-                  flag_repeat)
-            ?;
+        Ok(vec![])
     }
+}
+
+/// Group the measurements (those not reused from another commit) by
+/// their digest: for each, the commit measured, and its measurements
+/// from the last run that measured it.
+fn by_digest(measurements: Vec<Measurement>) -> HashMap<String, (String, Vec<Measurement>)> {
+    let mut measured: HashMap<String, (String, Vec<Measurement>)> = HashMap::new();
+    for m in measurements {
+        let digest = match m.digest {
+            Some(ref digest) if m.alias_of.is_none() => digest.clone(),
+            _ => continue,
+        };
+        let entry = measured.entry(digest).or_insert_with(|| (m.commit.clone(), vec![]));
+        if entry.0 != m.commit || entry.1.last().map(|l| &l.run) != Some(&m.run) {
+            *entry = (m.commit.clone(), vec![]);
+        }
+        entry.1.push(m);
+    }
+    measured
+}
 
+/// Check out `head_commit` again, and point HEAD back at `head`.
+fn restore_head(repo: &Repository, head: &Reference, head_commit: &Object) -> Result<()> {
+    repo.checkout_tree(head_commit, Some(&mut CheckoutBuilder::new()))
+        .chain_err(|| {
+            format!("failed to checkout original HEAD `{}`",
+                    git::short_id(head_commit))
+        })?;
+    let name = head.name().ok_or("HEAD not utf-8")?;
+    repo.set_head(name)
+        .chain_err(|| format!("failed to restore original HEAD `{}`", name))?;
     Ok(())
 }
 
+/// What `bench` is going to do, worked out from its `Config` once and
+/// shared by `dry_run` and `measure`.
+struct Plan<'a> {
+    config: &'a Config<'a>,
+    args: &'a BenchArgs,
+    repo: &'a Repository,
+    data_path: &'a Path,
+    run_id: String, // the id this session is recorded under
+    ignored_paths: Vec<PathBuf>, // files that may be dirty (those that exist)
+    revisions: Vec<Object<'a>>,
+    builds: Vec<(Variant, BuildConfig)>, // one per variant
+    runs: Vec<Run<'a>>, // at each commit, for each variant, in order
+    checkout: bool, // whether the commits are checked out to build them
+    cache_dir: PathBuf,
+
+    // whether the working tree (with the changes that `--ignore-dirty`
+    // matches) is built rather than HEAD; see `build_commit`
+    dirty_build: bool,
+
+    sched: &'a Sched,
+    seed: Option<u64>, // for `--shuffle`
+    repeat: Repeat,
+    storage: Storage,
+}
+
+/// One invocation of the bench executables for a commit.
+#[derive(Copy, Clone)]
+struct Run<'a> {
+    bench_name: &'a str, // passed to the executables as a filter ("" for all)
+    harness_args: &'a [String], // passed to the executables as well
+    param: Option<(&'a str, &'a str)>, // environment variable being swept, and its value
+    sched: &'a Sched, // CPUs and priority to run the executables with
+    index: usize, // which repetition this is, counting from 0
+    repeat: usize, // total number of repetitions
+}
+
+/// A benchmark name and parameter, which are repeated (and warmed up)
+/// together.
+type Group<'a> = (&'a str, Option<(&'a str, &'a str)>);
+
+/// A small (xorshift64*) random number generator, so that a seed
+/// gives the same order everywhere.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+/// The seed for `--shuffle`, if given: a number, or `random` to pick
+/// one.
+fn shuffle_seed(shuffle: &Option<String>) -> Result<Option<u64>> {
+    match *shuffle {
+        None => Ok(None),
+        Some(ref seed) if seed == "random" => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            Ok(Some(now.as_secs() ^ ((now.subsec_nanos() as u64) << 32) ^ process::id() as u64))
+        }
+        Some(ref seed) => {
+            match seed.parse() {
+                Ok(seed) => Ok(Some(seed)),
+                Err(_) => throw!("`--shuffle` seed should be a number, not `{}`", seed),
+            }
+        }
+    }
+}
+
+/// Plan the runs for each commit, in the order they are executed.
+fn plan_runs<'a>(bench_names: &'a [String],
+                 harness_args: &'a [String],
+                 sweep: Option<&'a Sweep>,
+                 sched: &'a Sched,
+                 repeat: usize)
+                 -> Vec<Run<'a>> {
+    let params: Vec<_> = match sweep {
+        Some(sweep) => sweep.values.iter().map(|v| Some((&sweep.name[..], &v[..]))).collect(),
+        None => vec![None],
+    };
+
+    let mut runs = vec![];
+    for bench_name in bench_names {
+        for &param in &params {
+            for index in 0..repeat {
+                runs.push(Run {
+                    bench_name: bench_name,
+                    harness_args: harness_args,
+                    param: param,
+                    sched: sched,
+                    index: index,
+                    repeat: repeat,
+                });
+            }
+        }
+    }
+    runs
+}
+
+/// Describe everything that `bench` would do, without checking
+/// anything out or writing any data.
+fn dry_run(plan: &Plan) -> Result<()> {
+    let config = plan.config;
+    let worktree = plan.revisions.iter().filter_map(|r| r.as_commit()).any(git::is_worktree);
+    let short_ids: Vec<_> = plan.revisions.iter()
+        .filter_map(|r| r.as_commit())
+        .map(git::label)
+        .collect();
+    println!("revisions: {}", short_ids.join(" "));
+
+    if config.stash || worktree {
+        let dirty = git::dirty_files(plan.repo, &plan.ignored_paths)?;
+        if dirty.is_empty() {
+            println!("working tree: clean");
+        } else {
+            println!("working tree: {} dirty files (bench would stash them)", dirty.len());
+        }
+    } else {
+        match git::check_clean(plan.repo, &plan.ignored_paths) {
+            Ok(()) => println!("working tree: clean"),
+            Err(e) => println!("working tree: {} (bench would refuse to run)", e),
+        }
+    }
+    for check in doctor::machine_checks() {
+        if let Some(problem) = check.problem {
+            let consequence = if config.strict { "bench would refuse to run" } else { "warning" };
+            println!("{}: {} ({}: {})", check.name, check.value, consequence, problem);
+        }
+    }
+
+    // Use the timings already recorded (if any) to guess how long
+    // each benchmark will take: for each test, the median of all
+    // measurements.
+    let mut timings = HashMap::new();
+    let stored = load_stored(plan)?;
+    for m in &stored {
+        if m.metric == "time" {
+            timings.entry(m.test.clone()).or_insert(vec![]).push(m.value);
+        }
+    }
+    let medians: HashMap<_, _> = timings.into_iter()
+        .map(|(test, mut times)| {
+            times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            (test, times[times.len() / 2])
+        })
+        .collect();
+
+    if let Some(seed) = plan.seed {
+        println!("shuffle: runs at each commit shuffled with seed {}", seed);
+    }
+    if let Some(run) = plan.runs.first() {
+        if !run.sched.cpus.is_empty() {
+            println!("cpus: benchmarks pinned to {}", run.sched.describe_cpus());
+        }
+        if let Some(nice) = run.sched.nice {
+            println!("nice: benchmarks run with niceness {}", nice);
+        }
+    }
+    if let Repeat::Auto { target_ci, max } = plan.repeat {
+        println!("repeat: each benchmark {} times, then until the confidence interval of its \
+                  median is below {}% (at most {} times)",
+                 plan.repeat.planned(),
+                 target_ci * 100.0,
+                 max);
+    }
+
+    println!("plan:");
+    let mut rng = plan.seed.map(Rng::new);
+    let mut estimate = 0.0;
+    let mut unknown_runs = 0;
+    let mut digests: HashMap<_, _> = by_digest(stored)
+        .into_iter()
+        .map(|(digest, (commit, _))| (digest, commit))
+        .collect();
+    for (commit, short_id) in plan.revisions.iter().filter_map(|r| r.as_commit()).zip(&short_ids) {
+        for &(ref variant, ref build) in &plan.builds {
+            let label = if variant.name.is_empty() {
+                short_id.clone()
+            } else {
+                format!("{} {}", short_id, variant.name)
+            };
+            let dir = plan.cache_dir.join(cache::key(plan.repo, commit, build)?);
+            let entry = if config.build_only || plan.dirty_build {
+                None
+            } else {
+                cache::lookup(&dir)?
+            };
+            match entry {
+                Some(ref entry) => {
+                    println!("  [{}] using cached build in `{}`", label, dir.display());
+                    let digest = run_digest(plan, variant, build, entry)?;
+                    let earlier = digests.get(&digest).filter(|&original| original != short_id);
+                    if let Some(original) = earlier {
+                        println!("  [{}] identical to `{}`, results reused", label, original);
+                        continue;
+                    }
+                    digests.insert(digest, short_id.clone());
+                }
+                None => {
+                    if plan.checkout {
+                        println!("  [{}] git checkout {}", label, commit.id());
+                    }
+                    if plan.dirty_build {
+                        println!("  [{}] (with the uncommitted changes that `--ignore-dirty` \
+                                  matches, so not cached)",
+                                 label);
+                    }
+                    if config.clean {
+                        println!("  [{}] {:?}", label, cache::clean_command(build)?);
+                    }
+                    println!("  [{}] {:?}", label, cache::build_command(build));
+                }
+            }
+
+            if config.calibrate {
+                println!("  [{}] cargo-chrono calibrate", label);
+            }
+            let mut commit_runs = plan.runs.clone();
+            if let Some(ref mut rng) = rng {
+                rng.shuffle(&mut commit_runs);
+                if warmup_runs(config) > 0 {
+                    println!("  [{}] {} warmup run(s) of each benchmark (not recorded)",
+                             label,
+                             warmup_runs(config));
+                }
+            }
+            for run in &commit_runs {
+                if rng.is_none() && run.index == 0 && warmup_runs(config) > 0 {
+                    println!("  [{}] {} warmup run(s) of the following (not recorded)",
+                             label,
+                             warmup_runs(config));
+                }
+                match entry {
+                    Some(ref entry) => {
+                        for executable in &entry.executables {
+                            if config.cachegrind {
+                                println!("  [{}] {:?}", label, list_command(executable, build, run));
+                                println!("  [{}] valgrind --tool=cachegrind {} --exact <each \
+                                          benchmark listed>",
+                                         label,
+                                         executable.path.display());
+                            } else {
+                                println!("  [{}] {:?}",
+                                         label,
+                                         bench_command(executable, build, run));
+                            }
+                        }
+                    }
+                    None => {
+                        let param = match run.param {
+                            Some((name, value)) => format!("{}={} ", name, value),
+                            None => String::new(),
+                        };
+                        if config.cachegrind {
+                            println!("  [{}] {}valgrind --tool=cachegrind <bench executables> {} \
+                                      --exact <each benchmark matching `{}`>",
+                                     label,
+                                     param,
+                                     run.harness_args.join(" "),
+                                     run.bench_name);
+                        } else {
+                            println!("  [{}] {}<bench executables> --bench {} {}",
+                                     label,
+                                     param,
+                                     run.harness_args.join(" "),
+                                     run.bench_name);
+                        }
+                    }
+                }
+
+                let matching: Vec<_> = medians.iter()
+                    .filter(|&(test, _)| test.contains(run.bench_name))
+                    .collect();
+                if matching.is_empty() {
+                    unknown_runs += 1;
+                }
+                let estimate_run = if config.cachegrind {
+                    estimate_cachegrind
+                } else {
+                    estimate_libtest
+                };
+                let mut times = 1;
+                if run.index == 0 {
+                    times += warmup_runs(config);
+                }
+                estimate += times as f64 *
+                            matching.iter().map(|&(_, &time)| estimate_run(time)).sum::<f64>();
+            }
+        }
+    }
+
+    let secs = (estimate / 1_000_000_000.0) as u64;
+    println!("estimated duration: {}h {:02}m {:02}s (not counting builds)",
+             secs / 3600,
+             secs / 60 % 60,
+             secs % 60);
+    if unknown_runs > 0 {
+        println!("no timings recorded for {} of the runs above", unknown_runs);
+    }
+
+    Ok(())
+}
+
+/// Roughly how long (in ns) libtest takes to benchmark a test that
+/// runs in `time` ns/iter. Each round takes 50 samples of `n` and 50
+/// samples of `5 * n` iterations, where `n` is chosen so that a
+/// sample takes at least 1ms. We assume that a single round suffices.
+fn estimate_libtest(time: f64) -> f64 {
+    300.0 * time.max(1_000_000.0)
+}
+
+/// Roughly how long (in ns) cachegrind takes to run a test that runs
+/// in `time` ns/iter once. Simulating the caches makes it around 50
+/// times slower than running natively.
+fn estimate_cachegrind(time: f64) -> f64 {
+    50.0 * time
+}
+
+/// Find the bench executables for `commit`, building them if they
+/// are not already in the cache (or `config.build_only` is set). If
+/// `plan.checkout`, the commit is checked out first (but only if a
+/// build is needed). If `plan.dirty_build`, the working tree differs from
+/// the commit, so it is always built, and kept out of the cache. Also
+/// returns how long the build took (in ns), if there was one.
+fn build_commit<WB>(bar: &mut ProgressBar<WB>,
+                    plan: &Plan,
+                    commit: &Commit,
+                    build: &BuildConfig)
+                    -> Result<(cache::Entry, Option<u64>)>
+    where WB: Write
+{
+    let key = cache::key(plan.repo, commit, build)?;
+    let dir = if plan.dirty_build {
+        plan.cache_dir.join("uncommitted").join(key)
+    } else {
+        plan.cache_dir.join(key)
+    };
+    if !plan.config.build_only && !plan.dirty_build {
+        if let Some(entry) = cache::lookup(&dir)? {
+            bar.message(&format!("using cached build of `{}`", git::label(commit)));
+            bar.inc();
+            return Ok((entry, None));
+        }
+    }
+
+    if plan.checkout {
+        bar.message(&format!("checking out `{}`", git::label(commit)));
+        git::checkout_commit(plan.repo, commit)
+            .chain_err(|| format!("failed to checkout commit `{}`", git::label(commit)))?;
+    }
+
+    if plan.config.clean {
+        bar.message(&format!("cleaning before building `{}`", git::label(commit)));
+        let mut cargo = cache::clean_command(build)?;
+        let output = cargo.output().chain_err(|| "error executing `cargo clean`")?;
+        if !output.status.success() {
+            bail!("`{:?}` exited with error-code `{}`", cargo, output.status);
+        }
+    }
+
+    bar.message(&format!("building `{}`", git::label(commit)));
+    bar.inc();
+    let (entry, elapsed) = cache::build(&dir, build)
+        .chain_err(|| format!("failed to build `{}`", git::label(commit)))?;
+    let build_time = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    Ok((entry, Some(build_time)))
+}
+
 fn run_bench<F, WB>(bar: &mut ProgressBar<WB>,
-                    repo: &Repository,
                     writer: &mut csv::Writer<F>,
-                    bench_flags: &[String],
-                    bench_names: &[String],
-                    flag_repeat: usize)
-                    -> Result<()>
+                    plan: &Plan,
+                    template: &Measurement,
+                    &(ref variant, ref build): &(Variant, BuildConfig),
+                    entry: &cache::Entry,
+                    runs: &[Run])
+                    -> Result<Vec<Measurement>>
     where F: Write,
           WB: Write
 {
-    // how many total times will we run cargo
-    let mut tick = |title: &str| {
-        bar.message(title);
-        bar.inc();
-    };
+    let (config, args) = (plan.config, plan.args);
+    let commit = &template.commit;
+    let mut measurements = vec![];
 
-    // find the current commit sha1 hash
-    let commit = git::short_id(&repo.head()
-        .chain_err(|| "failed to fetch HEAD from repo")?
-        .peel(ObjectType::Commit)
-        .chain_err(|| "HEAD not a commit")?);
+    let mut with_args = if args.cargo_flags.is_empty() && args.harness_args.is_empty() {
+        String::new()
+    } else {
+        format!(" with {}", args.describe())
+    };
+    if !variant.name.is_empty() {
+        with_args.push_str(&format!(" [{}]", variant.name));
+    }
 
-    {
-        tick(&format!("building `{}`", commit));
-        let mut cargo = Command::new("cargo");
-        cargo.arg("bench");
-        for bench_flag in bench_flags {
-            cargo.arg(bench_flag);
-        }
-        cargo.arg("--no-run");
-        let output = cargo.output().chain_err(|| "error executing `cargo bench`")?;
-        if !output.status.success() {
-            bail!("`{:?}` exited with error-code `{}`", cargo, output.status);
+    // The first runs after switching commits (or benchmarks) are
+    // slower, so discard a few. Shuffled runs switch benchmarks all the
+    // time, so then only switching commits counts.
+    let label = format!("`{}`{}", commit, with_args);
+    let mut warmed_up = vec![];
+    if config.shuffle.is_some() {
+        for run in runs {
+            warm_up_once(bar, &mut warmed_up, config, &label, build, entry, run)?;
         }
     }
 
-    // for each benchmark name they gave us...
-    for bench_name in bench_names {
-        // repeat N times...
-        for i in 0..flag_repeat {
-            // ...run cargo and save the output.
-            if !bench_name.is_empty() {
-                tick(&format!("testing `{}` from `{}` (run {}/{})",
-                              bench_name,
-                              commit,
-                              i + 1,
-                              flag_repeat));
+    // for each benchmark (and parameter): the runs done, and their measurements
+    let mut groups: HashMap<Group, (usize, Vec<Measurement>)> = HashMap::new();
+    for run in runs {
+        warm_up_once(bar, &mut warmed_up, config, &label, build, entry, run)?;
+
+        // ...run each bench executable and save the output.
+        let describe = |run: &Run| {
+            let with_param = match run.param {
+                Some((name, value)) => format!(" at {}={}", name, value),
+                None => String::new(),
+            };
+            let count = match plan.repeat {
+                Repeat::Fixed(_) => format!("{}/{}", run.index + 1, run.repeat),
+                Repeat::Auto { max, .. } => format!("{}, at most {}", run.index + 1, max),
+            };
+            if !run.bench_name.is_empty() {
+                format!("testing `{}` from `{}`{}{} (run {})",
+                        run.bench_name,
+                        commit,
+                        with_args,
+                        with_param,
+                        count)
             } else {
-                tick(&format!("testing `{}` (run {}/{})", commit, i + 1, flag_repeat));
+                format!("testing `{}`{}{} (run {})", commit, with_args, with_param, count)
             }
-            let mut cargo = Command::new("cargo");
-            cargo.arg("bench");
-            for bench_flag in bench_flags {
-                cargo.arg(bench_flag);
+        };
+        bar.message(&describe(run));
+        bar.inc();
+        let results = run_once(writer, plan, template, build, entry, run)?;
+        measurements.extend(results.iter().cloned());
+        let group = groups.entry((run.bench_name, run.param)).or_insert((0, vec![]));
+        group.0 += 1;
+        group.1.extend(results);
+
+        // With `--repeat auto`, once the planned runs of a benchmark
+        // are done, keep going until its results are stable.
+        if let Repeat::Auto { target_ci, max } = plan.repeat {
+            if group.0 < run.repeat {
+                continue;
             }
-            if !bench_name.is_empty() {
-                cargo.arg(bench_name);
+            while group.0 < max && !is_stable(&group.1, target_ci) {
+                let extra = Run { index: group.0, ..*run };
+                bar.total += 1;
+                bar.message(&describe(&extra));
+                bar.inc();
+                let results = run_once(writer, plan, template, build, entry, &extra)?;
+                measurements.extend(results.iter().cloned());
+                group.0 += 1;
+                group.1.extend(results);
             }
-            let output = cargo.output()
-                .chain_err(|| "error executing `cargo bench`")?;
-            if !output.status.success() {
-                bail!("`{:?}` exited with error-code `{}`", cargo, output.status);
+
+            // Record how many samples each test ended up with.
+            let mut samples: Vec<(Measurement, usize)> = vec![];
+            for m in &group.1 {
+                if !STABLE_METRICS.contains(&&m.metric[..]) {
+                    continue;
+                }
+                match samples.iter().position(|s| s.0.test == m.test) {
+                    Some(i) => samples[i].1 += 1,
+                    None => samples.push((m.clone(), 1)),
+                }
             }
-            let output_str = match str::from_utf8(&output.stdout) {
-                Ok(s) => s,
-                Err(_) => throw!("`cargo bench` did not output utf-8"),
-            };
+            for (m, count) in samples {
+                let measurement = Measurement {
+                    metric: "samples".to_string(),
+                    unit: data::default_unit("samples").to_string(),
+                    value: count as f64,
+                    spread: None,
+                    ..m
+                };
+                data::write_measurement(writer, &measurement)?;
+                measurements.push(measurement);
+            }
+        }
+    }
+
+    Ok(measurements)
+}
+
+/// Whether the medians of the tests in `measurements` are known
+/// precisely enough: for each test, the 95% confidence interval of the
+/// median must be narrower than `target_ci` (relative to the median).
+fn is_stable(measurements: &[Measurement], target_ci: f64) -> bool {
+    let mut values: HashMap<(&str, &str), Vec<f64>> = HashMap::new();
+    for m in measurements {
+        if STABLE_METRICS.contains(&&m.metric[..]) {
+            values.entry((&m.test, &m.metric)).or_insert(vec![]).push(m.value);
+        }
+    }
+    values.values_mut().all(|values| median_ci(values) <= target_ci)
+}
 
-            // Grep through the output and collect new data, appending it to
-            // the data file as we go. The data has this format:
-            //
-            // (label, test_name, time, variance)
-            for line in output_str.lines() {
-                if let Some(captures) = BENCH_RE.captures(line) {
-                    let (name, time_str, variance_str) = (&captures[1], &captures[2], &captures[3]);
-                    let time_str: String = time_str.chars().filter(|&c| c != ',').collect();
-                    let variance_str: String = variance_str.chars().filter(|&c| c != ',').collect();
-                    let data = (&commit, name, time_str, variance_str);
-                    writer.encode(data)
-                        .chain_err(|| format!("failed to write data for test `{}`", name))?;
+/// The width of the (roughly) 95% confidence interval of the median of
+/// `values`, relative to the median. This uses order statistics, so it
+/// does not assume anything about the distribution of the values.
+fn median_ci(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let n = values.len() as f64;
+    let half_width = 1.96 * n.sqrt() / 2.0;
+    let low = cmp::max((n / 2.0 - half_width).floor() as usize, 1) - 1;
+    let high = cmp::min((n / 2.0 + half_width).ceil() as usize, values.len()) - 1;
+    let width = values[high] - values[low];
+    let median = values[values.len() / 2].abs();
+    if width == 0.0 {
+        0.0
+    } else if median == 0.0 {
+        f64::INFINITY
+    } else {
+        width / median
+    }
+}
+
+/// Time the calibration workload, which does not change from commit
+/// to commit, so that the results can be corrected for changes in the
+/// speed of the machine.
+fn run_calibration(template: &Measurement, sched: &Sched) -> Result<Measurement> {
+    let mut command = calibrate::command(sched)?;
+    let output = command.output().chain_err(|| "error executing the calibration workload")?;
+    if !output.status.success() {
+        bail!("`{:?}` exited with error-code `{}`", command, output.status);
+    }
+    let output_str = String::from_utf8_lossy(&output.stdout);
+    let captures = match output_str.lines().filter_map(|l| BENCH_RE.captures(l)).next() {
+        Some(c) => c,
+        None => throw!("unexpected output from the calibration workload: `{}`", output_str),
+    };
+    Ok(Measurement {
+        test: "calibration".to_string(),
+        metric: "calibration".to_string(),
+        unit: data::default_unit("calibration").to_string(),
+        value: parse_number(&captures[2], "time")?,
+        spread: Some(parse_number(&captures[3], "variance")?),
+        ..template.clone()
+    })
+}
+
+/// Do the warmup runs for the benchmark (and parameter) of `run`,
+/// unless they were done already.
+fn warm_up_once<'a, WB: Write>(bar: &mut ProgressBar<WB>,
+                               warmed_up: &mut Vec<Group<'a>>,
+                               config: &Config,
+                               label: &str,
+                               build: &BuildConfig,
+                               entry: &cache::Entry,
+                               run: &Run<'a>)
+                               -> Result<()> {
+    let group = (run.bench_name, run.param);
+    if warmed_up.contains(&group) {
+        return Ok(());
+    }
+    warmed_up.push(group);
+    for i in 0..warmup_runs(config) {
+        bar.message(&format!("warming up {} (run {}/{})", label, i + 1, warmup_runs(config)));
+        bar.inc();
+        warm_up(build, entry, run)?;
+    }
+    Ok(())
+}
+
+/// How many warmup runs to do before measuring each benchmark. Under
+/// cachegrind, there is nothing to warm up.
+fn warmup_runs(config: &Config) -> usize {
+    if config.cachegrind {
+        0
+    } else {
+        config.warmup
+    }
+}
+
+/// Run each bench executable once, discarding the results.
+fn warm_up(build: &BuildConfig, entry: &cache::Entry, run: &Run) -> Result<()> {
+    for executable in &entry.executables {
+        let mut bench = bench_command(executable, build, run);
+        let status = bench.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .chain_err(|| format!("error executing `{}`", executable.name))?;
+        if !status.success() {
+            bail!("`{:?}` exited with error-code `{}`", bench, status);
+        }
+    }
+    Ok(())
+}
+
+/// Run each bench executable once, recording the results.
+fn run_once<F: Write>(writer: &mut csv::Writer<F>,
+                      plan: &Plan,
+                      template: &Measurement,
+                      build: &BuildConfig,
+                      entry: &cache::Entry,
+                      run: &Run)
+                      -> Result<Vec<Measurement>> {
+    let mut measurements = vec![];
+    for executable in &entry.executables {
+        // what all measurements from this executable have in common
+        let template = Measurement {
+            package: Some(executable.package.clone()).filter(|p| !p.is_empty()),
+            target: Some(executable.name.clone()),
+            param: run.param.map(|(name, value)| format!("{}={}", name, value)),
+            ..template.clone()
+        };
+
+        if plan.config.cachegrind {
+            for (test, counts) in run_cachegrind(executable, build, run)? {
+                write_counts(writer, &mut measurements, &template, &test, &counts)?;
+            }
+            continue;
+        }
+
+        let mut bench = bench_command(executable, build, run);
+        let (output, usage) = rusage::output_with_usage(&mut bench)
+            .chain_err(|| format!("error executing `{}`", executable.name))?;
+        if !output.status.success() {
+            bail!("`{:?}` exited with error-code `{}`", bench, output.status);
+        }
+        let output_str = match str::from_utf8(&output.stdout) {
+            Ok(s) => s,
+            Err(_) => throw!("`{}` did not output utf-8", executable.name),
+        };
+
+        // Grep through the output and collect new data, appending it to
+        // the data file as we go. The data has this format:
+        //
+        // (label, test_name, value, spread, alias_of, run, package, target, variant, param,
+        //  metric, unit)
+        for line in output_str.lines() {
+            if let Some(captures) = BENCH_RE.captures(line) {
+                let (name, time_str, variance_str) = (&captures[1], &captures[2], &captures[3]);
+                let measurement = Measurement {
+                    test: name.to_string(),
+                    value: parse_number(time_str, "time")?,
+                    spread: Some(parse_number(variance_str, "variance")?),
+                    ..template.clone()
+                };
+                data::write_measurement(writer, &measurement)?;
+                measurements.push(measurement);
+
+                // libtest reports throughput if the benchmark sets `b.bytes`
+                if let Some(throughput_str) = captures.get(4) {
+                    let measurement = Measurement {
+                        test: name.to_string(),
+                        metric: "throughput".to_string(),
+                        unit: data::default_unit("throughput").to_string(),
+                        value: parse_number(throughput_str.as_str(), "throughput")?,
+                        ..template.clone()
+                    };
+                    data::write_measurement(writer, &measurement)?;
+                    measurements.push(measurement);
                 }
             }
         }
+
+        // `iai` harnesses run themselves under cachegrind.
+        for (test, counts) in cachegrind::parse_iai(output_str) {
+            write_counts(writer, &mut measurements, &template, &test, &counts)?;
+        }
+
+        // Also record the resources used by the process as a
+        // whole. These are attributed to the benchmark name we
+        // gave it (or, if none, to the bench target).
+        if let Some(usage) = usage {
+            let test = if run.bench_name.is_empty() {
+                &executable.name[..]
+            } else {
+                run.bench_name
+            };
+            for (metric, value) in usage.metrics() {
+                let measurement = Measurement {
+                    test: test.to_string(),
+                    metric: metric.to_string(),
+                    unit: data::default_unit(metric).to_string(),
+                    value: value as f64,
+                    ..template.clone()
+                };
+                data::write_measurement(writer, &measurement)?;
+                measurements.push(measurement);
+            }
+        }
     }
 
+    Ok(measurements)
+}
+
+/// Parse a number as printed by libtest, e.g. `12,459,703`.
+fn parse_number(s: &str, what: &str) -> Result<f64> {
+    let digits: String = s.chars().filter(|&c| c != ',').collect();
+    digits.parse().chain_err(|| format!("invalid {} `{}`", what, s))
+}
+
+/// Record the cachegrind counts of `test`.
+fn write_counts<F: Write>(writer: &mut csv::Writer<F>,
+                          measurements: &mut Vec<Measurement>,
+                          template: &Measurement,
+                          test: &str,
+                          counts: &Counts)
+                          -> Result<()> {
+    for (metric, value) in counts.metrics() {
+        let measurement = Measurement {
+            test: test.to_string(),
+            metric: metric.to_string(),
+            unit: data::default_unit(metric).to_string(),
+            value: value as f64,
+            ..template.clone()
+        };
+        data::write_measurement(writer, &measurement)?;
+        measurements.push(measurement);
+    }
     Ok(())
 }
+
+/// Run each benchmark in `executable` once (as libtest does for
+/// `cargo test`) under cachegrind, and collect the counts.
+fn run_cachegrind(executable: &cache::Executable,
+                  build: &BuildConfig,
+                  run: &Run)
+                  -> Result<Vec<(String, Counts)>> {
+    let mut list = list_command(executable, build, run);
+    let output = list.stderr(Stdio::null())
+        .output()
+        .chain_err(|| format!("error executing `{}`", executable.name))?;
+    if !output.status.success() {
+        bail!("`{:?}` exited with error-code `{}`", list, output.status);
+    }
+    let output_str = match str::from_utf8(&output.stdout) {
+        Ok(s) => s,
+        Err(_) => throw!("`{}` did not output utf-8", executable.name),
+    };
+
+    // `iai` harnesses ignore `--list`; they run themselves under
+    // cachegrind and print the results.
+    let results = cachegrind::parse_iai(output_str);
+    if !results.is_empty() {
+        return Ok(results.into_iter().filter(|r| r.0.contains(run.bench_name)).collect());
+    }
+
+    let out_file = env::temp_dir().join(format!("chrono-cachegrind-{}.out", process::id()));
+    let mut results = vec![];
+    for test in cachegrind::parse_list(output_str) {
+        let mut valgrind = cachegrind_command(executable, build, run, &test, &out_file);
+        let status = valgrind.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .chain_err(|| "error executing `valgrind` (is it installed?)")?;
+        if !status.success() {
+            bail!("`{:?}` exited with error-code `{}`", valgrind, status);
+        }
+        results.push((test, cachegrind::read_counts(&out_file)?));
+    }
+    let _ = fs::remove_file(&out_file);
+    Ok(results)
+}
+
+/// A command to run `program` for `executable`, in its directory and
+/// environment.
+fn harness_command<P: AsRef<OsStr>>(program: P,
+                                    executable: &cache::Executable,
+                                    build: &BuildConfig,
+                                    run: &Run)
+                                    -> Command {
+    let mut command = Command::new(program);
+    command.current_dir(&executable.cwd);
+    for &(ref key, ref value) in &build.env {
+        command.env(key, value);
+    }
+    if let Some((name, value)) = run.param {
+        command.env(name, value);
+    }
+    run.sched.apply(&mut command);
+    command
+}
+
+fn bench_command(executable: &cache::Executable, build: &BuildConfig, run: &Run) -> Command {
+    let mut bench = harness_command(&executable.path, executable, build, run);
+    bench.arg("--bench");
+    bench.args(run.harness_args);
+    if !run.bench_name.is_empty() {
+        bench.arg(run.bench_name);
+    }
+    bench
+}
+
+/// A command listing the benchmarks in `executable` that match the
+/// run's filter.
+fn list_command(executable: &cache::Executable, build: &BuildConfig, run: &Run) -> Command {
+    let mut list = harness_command(&executable.path, executable, build, run);
+    list.arg("--list");
+    if !run.bench_name.is_empty() {
+        list.arg(run.bench_name);
+    }
+    list
+}
+
+/// A command running just `test` from `executable`, once, under
+/// cachegrind.
+fn cachegrind_command(executable: &cache::Executable,
+                      build: &BuildConfig,
+                      run: &Run,
+                      test: &str,
+                      out_file: &Path)
+                      -> Command {
+    let mut valgrind = harness_command("valgrind", executable, build, run);
+    valgrind.arg("--tool=cachegrind");
+    valgrind.arg("--cache-sim=yes");
+    valgrind.arg(format!("--cachegrind-out-file={}", out_file.display()));
+    valgrind.arg(&executable.path);
+    valgrind.args(run.harness_args);
+    valgrind.arg("--exact");
+    valgrind.arg(test);
+    valgrind
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_shuffle_seed() {
+        assert_eq!(shuffle_seed(&None).unwrap(), None);
+        assert_eq!(shuffle_seed(&Some("42".to_string())).unwrap(), Some(42));
+        assert!(shuffle_seed(&Some("random".to_string())).unwrap().is_some());
+        assert!(shuffle_seed(&Some("-1".to_string())).is_err());
+    }
+
+    #[test]
+    fn shuffle_depends_only_on_seed() {
+        let shuffled = |seed| {
+            let mut items: Vec<_> = (0..20).collect();
+            Rng::new(seed).shuffle(&mut items);
+            items
+        };
+        assert_eq!(shuffled(42), shuffled(42));
+        assert!(shuffled(42) != shuffled(43));
+
+        let mut sorted = shuffled(42);
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zero_seed_still_shuffles() {
+        let mut rng = Rng::new(0);
+        assert!(rng.next() != rng.next());
+    }
+
+    #[test]
+    fn parses_sweep() {
+        let sweep = Sweep::parse("RAYON_NUM_THREADS=1, 2,4,").unwrap();
+        assert_eq!(sweep.name, "RAYON_NUM_THREADS");
+        assert_eq!(sweep.values, vec!["1", "2", "4"]);
+        assert!(Sweep::parse("RAYON_NUM_THREADS").is_err());
+        assert!(Sweep::parse("RAYON_NUM_THREADS=").is_err());
+    }
+
+    #[test]
+    fn parses_repeat() {
+        match Repeat::parse("3", "2%", 50).unwrap() {
+            Repeat::Fixed(3) => {}
+            repeat => panic!("unexpected {:?}", repeat),
+        }
+        match Repeat::parse("auto", "2%", 50).unwrap() {
+            Repeat::Auto { target_ci, max: 50 } => assert!((target_ci - 0.02).abs() < 1e-9),
+            repeat => panic!("unexpected {:?}", repeat),
+        }
+        assert!(Repeat::parse("often", "2%", 50).is_err());
+        assert!(Repeat::parse("auto", "0%", 50).is_err());
+    }
+
+    #[test]
+    fn median_ci_of_identical_values() {
+        assert_eq!(median_ci(&mut [5.0, 5.0, 5.0]), 0.0);
+        assert_eq!(median_ci(&mut [0.0, 0.0]), 0.0);
+        assert_eq!(median_ci(&mut [0.0, 0.0, 0.0, 1.0, -1.0]), f64::INFINITY);
+    }
+
+    #[test]
+    fn median_ci_narrows_with_more_samples() {
+        // (the order statistics 40 and 60 of 1..=100, around a median of 51)
+        let mut values: Vec<_> = (1..101).rev().map(|v| v as f64).collect();
+        assert!((median_ci(&mut values) - 20.0 / 51.0).abs() < 1e-9);
+
+        let mut few = [9.0, 10.0, 11.0, 10.5, 9.5];
+        let mut many: Vec<_> = (0..50).flat_map(|_| few.to_vec()).collect();
+        assert!(median_ci(&mut many) < median_ci(&mut few));
+    }
+
+    #[test]
+    fn stable_once_every_test_is() {
+        let sample = |test: &str, value| Measurement::new("abc", test, "time", value);
+        let mut measurements: Vec<_> = (0..20).map(|_| sample("nbody", 10.0)).collect();
+        assert!(is_stable(&measurements, 0.02));
+        measurements.extend((0..20).map(|i| sample("parse", 10.0 + i as f64)));
+        assert!(!is_stable(&measurements, 0.02));
+    }
+}
@@ -0,0 +1,141 @@
+use errors::*;
+
+/// Flags of `cargo bench` that take a value. Unless written as
+/// `--flag=value`, the value is the following argument.
+const FLAGS_WITH_VALUES: &'static [&'static str] = &["--bench",
+                                                      "--bin",
+                                                      "--color",
+                                                      "--config",
+                                                      "--example",
+                                                      "--exclude",
+                                                      "--features",
+                                                      "--jobs",
+                                                      "--manifest-path",
+                                                      "--message-format",
+                                                      "--package",
+                                                      "--profile",
+                                                      "--target",
+                                                      "--target-dir",
+                                                      "--test",
+                                                      "-F",
+                                                      "-Z",
+                                                      "-j",
+                                                      "-p"];
+
+/// Whether the cargo flag `flag` is followed by a value.
+pub fn takes_value(flag: &str) -> bool {
+    FLAGS_WITH_VALUES.contains(&flag)
+}
+
+/// The arguments given after `bench`, sorted by where they go.
+#[derive(Clone, Debug, Default)]
+pub struct BenchArgs {
+    // flags for cargo itself, each followed by its value (if any)
+    pub cargo_flags: Vec<String>,
+
+    // names of benchmarks to run; each is measured separately
+    pub filters: Vec<String>,
+
+    // arguments after a `--`, passed on to the bench harness
+    pub harness_args: Vec<String>,
+}
+
+impl BenchArgs {
+    /// Sort `options` into cargo flags, benchmark filters and harness
+    /// arguments, the same way that `cargo bench` does.
+    pub fn parse(options: &[String]) -> Result<BenchArgs> {
+        let mut args = BenchArgs::default();
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            if option == "--" {
+                args.harness_args.extend(options.cloned());
+                break;
+            } else if option.starts_with("-") {
+                args.cargo_flags.push(option.clone());
+                if takes_value(option) {
+                    match options.next() {
+                        Some(value) => args.cargo_flags.push(value.clone()),
+                        None => throw!("cargo flag `{}` requires a value", option),
+                    }
+                }
+            } else {
+                args.filters.push(option.clone());
+            }
+        }
+        Ok(args)
+    }
+
+    /// A short description of the non-default arguments, for progress
+    /// messages and run metadata.
+    pub fn describe(&self) -> String {
+        let mut parts = vec![];
+        if !self.cargo_flags.is_empty() {
+            parts.push(format!("cargo flags `{}`", self.cargo_flags.join(" ")));
+        }
+        if !self.harness_args.is_empty() {
+            parts.push(format!("harness args `{}`", self.harness_args.join(" ")));
+        }
+        parts.join(", ")
+    }
+}
+
+/// The `--manifest-path` given in `cargo_flags`, if any.
+pub fn manifest_path(cargo_flags: &[String]) -> Option<&str> {
+    let mut flags = cargo_flags.iter();
+    while let Some(flag) = flags.next() {
+        if flag == "--manifest-path" {
+            return flags.next().map(|s| &s[..]);
+        } else if let Some(path) = flag.strip_prefix("--manifest-path=") {
+            return Some(path);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn sorts_flags_filters_and_harness_args() {
+        let args = BenchArgs::parse(&strings(&["--features",
+                                               "simd",
+                                               "nbody",
+                                               "-p",
+                                               "core",
+                                               "--release",
+                                               "parse",
+                                               "--",
+                                               "--nocapture",
+                                               "--"]))
+            .unwrap();
+        assert_eq!(args.cargo_flags, strings(&["--features", "simd", "-p", "core", "--release"]));
+        assert_eq!(args.filters, strings(&["nbody", "parse"]));
+        assert_eq!(args.harness_args, strings(&["--nocapture", "--"]));
+    }
+
+    #[test]
+    fn flag_with_value_attached() {
+        let args = BenchArgs::parse(&strings(&["--features=simd", "nbody"])).unwrap();
+        assert_eq!(args.cargo_flags, strings(&["--features=simd"]));
+        assert_eq!(args.filters, strings(&["nbody"]));
+    }
+
+    #[test]
+    fn flag_missing_its_value() {
+        assert!(BenchArgs::parse(&strings(&["nbody", "--bench"])).is_err());
+    }
+
+    #[test]
+    fn finds_manifest_path() {
+        assert_eq!(manifest_path(&strings(&["--manifest-path", "a/Cargo.toml"])),
+                   Some("a/Cargo.toml"));
+        assert_eq!(manifest_path(&strings(&["-p", "x", "--manifest-path=b/Cargo.toml"])),
+                   Some("b/Cargo.toml"));
+        assert_eq!(manifest_path(&strings(&["--release"])), None);
+    }
+}
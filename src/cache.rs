@@ -0,0 +1,400 @@
+use bench_args;
+use csv;
+use errors::*;
+use git2::{Commit, ObjectType, Oid, Repository};
+use rustc_serialize::json::Json;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str;
+use std::time::{Duration, Instant};
+use variant::Variant;
+
+/// A bench executable produced by `cargo bench --no-run`, copied into
+/// the cache.
+#[derive(Clone, Debug)]
+pub struct Executable {
+    pub package: String, // name of the package containing the bench target
+    pub name: String, // name of the bench target (e.g., `nbody`)
+    pub path: PathBuf, // location of the copy inside the cache
+    pub cwd: PathBuf, // directory that `cargo bench` would run it from
+}
+
+/// All bench executables built for one commit under one set of flags.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub executables: Vec<Executable>,
+}
+
+const MANIFEST: &'static str = "manifest.csv";
+
+/// Where the cache lives: `target/chrono/bin`.
+pub fn cache_dir(bench_flags: &[String]) -> Result<PathBuf> {
+    let metadata = metadata(bench_flags)?;
+    let target_dir = match metadata.find("target_directory").and_then(|t| t.as_string()) {
+        Some(t) => t,
+        None => throw!("`cargo metadata` did not report a target directory"),
+    };
+    Ok(Path::new(target_dir).join("chrono").join("bin"))
+}
+
+/// Run `cargo metadata` for the workspace that `cargo bench` would use.
+fn metadata(bench_flags: &[String]) -> Result<Json> {
+    let mut cargo = Command::new("cargo");
+    cargo.args(["metadata", "--format-version", "1", "--no-deps"]);
+    if let Some(manifest_path) = bench_args::manifest_path(bench_flags) {
+        cargo.arg("--manifest-path").arg(manifest_path);
+    }
+    let output = cargo.output().chain_err(|| "error executing `cargo metadata`")?;
+    if !output.status.success() {
+        bail!("`{:?}` exited with error-code `{}`", cargo, output.status);
+    }
+    let output_str = str::from_utf8(&output.stdout)
+        .chain_err(|| "`cargo metadata` did not output utf-8")?;
+    Json::from_str(output_str).chain_err(|| "`cargo metadata` did not output valid JSON")
+}
+
+/// How the bench executables are built: with which toolchain, which
+/// flags for `cargo bench` (including features), and which extra
+/// environment variables (e.g., `RUSTFLAGS`).
+#[derive(Clone, Debug)]
+pub struct BuildConfig {
+    pub toolchain: Option<String>, // e.g. `nightly`, for `cargo +nightly`
+    pub cargo_flags: Vec<String>,
+    pub env: Vec<(String, String)>,
+
+    // describes the compiler, so that builds from different compilers
+    // are not confused with one another (output of `rustc -vV`)
+    pub version: String,
+}
+
+impl BuildConfig {
+    pub fn new(bench_flags: &[String], variant: &Variant) -> Result<BuildConfig> {
+        let mut rustc = Command::new("rustc");
+        if let Some(ref toolchain) = variant.toolchain {
+            rustc.arg(format!("+{}", toolchain));
+        }
+        rustc.arg("-vV");
+        let output = rustc.output().chain_err(|| format!("error executing `{:?}`", rustc))?;
+        if !output.status.success() {
+            bail!("`{:?}` exited with error-code `{}`", rustc, output.status);
+        }
+        let version = match String::from_utf8(output.stdout) {
+            Ok(s) => s,
+            Err(_) => throw!("`rustc -vV` did not output utf-8"),
+        };
+
+        let mut cargo_flags = bench_flags.to_vec();
+        cargo_flags.extend(variant.cargo_flags.iter().cloned());
+        Ok(BuildConfig {
+            toolchain: variant.toolchain.clone(),
+            cargo_flags: cargo_flags,
+            env: variant.env.clone(),
+            version: version,
+        })
+    }
+}
+
+/// Compute the cache key for `commit` built under `config`. Besides
+/// those, the key covers whatever else in the environment changes the
+/// build (see `ambient`).
+pub fn key(repo: &Repository, commit: &Commit, config: &BuildConfig) -> Result<String> {
+    let id = commit.id().to_string();
+    let mut fields: Vec<(String, Vec<u8>)> = vec![("commit".to_string(), id.clone().into_bytes()),
+                                                  ("version".to_string(),
+                                                   config.version.clone().into_bytes())];
+    for flag in &config.cargo_flags {
+        fields.push(("cargo_flag".to_string(), flag.clone().into_bytes()));
+    }
+    for &(ref key, ref value) in &config.env {
+        fields.push((format!("env {}", key), value.clone().into_bytes()));
+    }
+    fields.extend(ambient(repo, commit)?);
+    let hash = stable_hash(&fields)?.to_string();
+    Ok(format!("{}-{}", &id[..10], &hash[..16]))
+}
+
+/// The environment variables and files that change what `cargo bench`
+/// builds from `commit`, beyond what the `BuildConfig` says: the
+/// `RUSTFLAGS`-like and `CARGO_*` variables, cargo's config files, and
+/// the `rust-toolchain` file that picks the compiler. Cargo and rustup
+/// look for the files in the current directory and its parents; inside
+/// the repository, they are taken from `commit`, which is what is
+/// checked out when it is built.
+fn ambient(repo: &Repository, commit: &Commit) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut fields = vec![];
+
+    let mut vars: Vec<_> = env::vars_os()
+        .filter_map(|(key, value)| key.into_string().ok().map(|k| (k, value)))
+        .filter(|&(ref key, _)| key.starts_with("CARGO_") || AMBIENT_VARS.contains(&&key[..]))
+        .collect();
+    vars.sort();
+    for (key, value) in vars {
+        fields.push((format!("env {}", key), value.to_string_lossy().into_owned().into_bytes()));
+    }
+
+    let current_dir = env::current_dir()
+        .and_then(|d| d.canonicalize())
+        .chain_err(|| "failed to find current dir")?;
+    let workdir = match repo.workdir() {
+        Some(w) => w.canonicalize().unwrap_or_else(|_| w.to_owned()),
+        None => throw!("cannot benchmark a bare repository"),
+    };
+    let tree = commit.tree().chain_err(|| format!("cannot read the tree of `{}`", commit.id()))?;
+    for dir in current_dir.ancestors() {
+        for name in AMBIENT_FILES {
+            let path = dir.join(name);
+            let contents = match path.strip_prefix(&workdir) {
+                Ok(relative) => {
+                    tree.get_path(relative)
+                        .and_then(|entry| entry.to_object(repo))
+                        .ok()
+                        .and_then(|o| o.as_blob().map(|b| b.content().to_vec()))
+                }
+                Err(_) => fs::read(&path).ok(),
+            };
+            fields.extend(contents.map(|c| (format!("file {}", path.display()), c)));
+        }
+    }
+
+    // (cargo's own config files are in `CARGO_HOME` itself)
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".cargo")));
+    if let Some(cargo_home) = cargo_home {
+        for name in &["config", "config.toml"] {
+            let path = cargo_home.join(name);
+            let contents = fs::read(&path).ok();
+            fields.extend(contents.map(|c| (format!("file {}", path.display()), c)));
+        }
+    }
+    Ok(fields)
+}
+
+/// Environment variables (besides `CARGO_*`) that change the build.
+const AMBIENT_VARS: &'static [&'static str] = &["RUSTC",
+                                                 "RUSTC_WRAPPER",
+                                                 "RUSTC_WORKSPACE_WRAPPER",
+                                                 "RUSTDOC",
+                                                 "RUSTDOCFLAGS",
+                                                 "RUSTFLAGS",
+                                                 "RUSTUP_TOOLCHAIN"];
+
+/// Files (relative to a directory) that change the build.
+const AMBIENT_FILES: &'static [&'static str] = &["rust-toolchain",
+                                                  "rust-toolchain.toml",
+                                                  ".cargo/config",
+                                                  ".cargo/config.toml"];
+
+/// Hash `fields` with SHA-1 (as git hashes its objects). Unlike
+/// `DefaultHasher`, this gives the same hash in every release of
+/// Rust, so the hash can be stored.
+fn stable_hash(fields: &[(String, Vec<u8>)]) -> Result<Oid> {
+    let mut bytes = vec![];
+    for &(ref name, ref value) in fields {
+        for field in &[name.as_bytes(), &value[..]] {
+            bytes.extend_from_slice(format!("{}:", field.len()).as_bytes());
+            bytes.extend_from_slice(field);
+        }
+    }
+    Oid::hash_object(ObjectType::Blob, &bytes).chain_err(|| "failed to hash")
+}
+
+/// Load the cache entry in `dir`, if it has been completely built.
+pub fn lookup(dir: &Path) -> Result<Option<Entry>> {
+    let manifest = dir.join(MANIFEST);
+    if !manifest.exists() {
+        return Ok(None);
+    }
+
+    let mut reader = csv::Reader::from_file(&manifest)
+        .chain_err(|| format!("cannot read `{}`", manifest.display()))?
+        .has_headers(false)
+        .flexible(true);
+    let mut executables = vec![];
+    for record in reader.records() {
+        let record = record.chain_err(|| format!("cannot decode `{}`", manifest.display()))?;
+        if record.len() < 3 {
+            bail!("cannot decode `{}`: expected at least 3 fields", manifest.display());
+        }
+        executables.push(Executable {
+            // entries from older versions did not record the package
+            package: record.get(3).cloned().unwrap_or_default(),
+            name: record[0].clone(),
+            path: dir.join(&record[1]),
+            cwd: PathBuf::from(&record[2]),
+        });
+    }
+
+    Ok(Some(Entry {
+        executables: executables,
+    }))
+}
+
+/// The command that builds the bench executables.
+pub fn build_command(config: &BuildConfig) -> Command {
+    let mut cargo = Command::new("cargo");
+    if let Some(ref toolchain) = config.toolchain {
+        cargo.arg(format!("+{}", toolchain));
+    }
+    cargo.arg("bench");
+    for bench_flag in &config.cargo_flags {
+        cargo.arg(bench_flag);
+    }
+    for &(ref key, ref value) in &config.env {
+        cargo.env(key, value);
+    }
+    cargo.arg("--no-run");
+    cargo.arg("--message-format=json");
+    cargo
+}
+
+/// The command that removes previous build results of the workspace's
+/// packages (but not of their dependencies), so that the next build of
+/// them starts from scratch. (A plain `cargo clean` would remove the
+/// whole target directory, and the cache with it.)
+pub fn clean_command(config: &BuildConfig) -> Result<Command> {
+    let metadata = metadata(&config.cargo_flags)?;
+    let mut cargo = Command::new("cargo");
+    if let Some(ref toolchain) = config.toolchain {
+        cargo.arg(format!("+{}", toolchain));
+    }
+    cargo.arg("clean");
+    if let Some(manifest_path) = bench_args::manifest_path(&config.cargo_flags) {
+        cargo.arg("--manifest-path").arg(manifest_path);
+    }
+    cargo.arg("--release"); // where `cargo bench` builds
+    let packages = metadata.find("packages").and_then(|p| p.as_array()).map(|p| &p[..]);
+    for package in packages.unwrap_or(&[]) {
+        if let Some(name) = package.find("name").and_then(|n| n.as_string()) {
+            cargo.arg("-p").arg(name);
+        }
+    }
+    Ok(cargo)
+}
+
+/// Build the bench executables for whatever is currently checked out
+/// and copy them into `dir`. The manifest is removed first and written
+/// last, so an interrupted build never looks like a valid entry. Also returns how
+/// long `cargo bench` took to build them.
+pub fn build(dir: &Path, config: &BuildConfig) -> Result<(Entry, Duration)> {
+    let mut cargo = build_command(config);
+    let start = Instant::now();
+    let output = cargo.output().chain_err(|| "error executing `cargo bench`")?;
+    let build_time = start.elapsed();
+    if !output.status.success() {
+        bail!("`{:?}` exited with error-code `{}`", cargo, output.status);
+    }
+    let output_str = match str::from_utf8(&output.stdout) {
+        Ok(s) => s,
+        Err(_) => throw!("`cargo bench` did not output utf-8"),
+    };
+
+    fs::create_dir_all(dir)
+        .chain_err(|| format!("failed to create cache directory `{}`", dir.display()))?;
+
+    // The entry is not valid while the executables are replaced.
+    let manifest = dir.join(MANIFEST);
+    if manifest.exists() {
+        fs::remove_file(&manifest)
+            .chain_err(|| format!("failed to remove `{}`", manifest.display()))?;
+    }
+
+    // map from package id to package name
+    let metadata = metadata(&config.cargo_flags)?;
+    let packages: HashMap<_, _> = metadata.find("packages")
+        .and_then(|p| p.as_array())
+        .map(|p| &p[..])
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|p| {
+            let id = p.find("id").and_then(|i| i.as_string());
+            let name = p.find("name").and_then(|n| n.as_string());
+            id.and_then(|id| name.map(|name| (id, name)))
+        })
+        .collect();
+
+    let current_dir = env::current_dir().chain_err(|| "failed to find current dir")?;
+    let mut executables = vec![];
+    for line in output_str.lines() {
+        let message = match Json::from_str(line) {
+            Ok(m) => m,
+            Err(_) => continue, // not a JSON message
+        };
+        if message.find("reason").and_then(|r| r.as_string()) != Some("compiler-artifact") {
+            continue;
+        }
+        if message.find_path(&["profile", "test"]).and_then(|t| t.as_boolean()) != Some(true) {
+            continue;
+        }
+        let executable = match message.find("executable").and_then(|e| e.as_string()) {
+            Some(e) => Path::new(e),
+            None => continue,
+        };
+        let name = match message.find_path(&["target", "name"]).and_then(|n| n.as_string()) {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+        let package = match message.find("package_id").and_then(|p| p.as_string()) {
+            Some(id) => packages.get(id).cloned().unwrap_or(id).to_string(),
+            None => String::new(),
+        };
+        let cwd = message.find("manifest_path")
+            .and_then(|m| m.as_string())
+            .and_then(|m| Path::new(m).parent())
+            .map(|p| p.to_owned())
+            .unwrap_or_else(|| current_dir.clone());
+
+        let file_name = match executable.file_name() {
+            Some(f) => f.to_owned(),
+            None => throw!("bench executable `{}` has no file name", executable.display()),
+        };
+        let path = dir.join(&file_name);
+        fs::copy(executable, &path).chain_err(|| {
+            format!("failed to copy `{}` into the cache", executable.display())
+        })?;
+        executables.push(Executable {
+            package: package,
+            name: name,
+            path: path,
+            cwd: cwd,
+        });
+    }
+
+    // (written aside and renamed into place, so it is never half there)
+    let partial = dir.join(format!("{}.partial", MANIFEST));
+    {
+        let mut writer = csv::Writer::from_file(&partial)
+            .chain_err(|| format!("failed to create `{}`", partial.display()))?;
+        for executable in &executables {
+            let file = executable.path.file_name().unwrap().to_string_lossy();
+            let cwd = executable.cwd.to_string_lossy();
+            writer.encode((&executable.name, file, cwd, &executable.package))
+                .chain_err(|| format!("failed to write `{}`", partial.display()))?;
+        }
+        writer.flush().chain_err(|| format!("failed to write `{}`", partial.display()))?;
+    }
+    fs::rename(&partial, &manifest)
+        .chain_err(|| format!("failed to create `{}`", manifest.display()))?;
+
+    Ok((Entry { executables: executables }, build_time))
+}
+
+/// Hash the contents of all the executables in `entry`, along with
+/// `context` (how they are run). Two commits with the same digest will
+/// produce the same measurements (modulo noise), so there is no need
+/// to run both. The digest is stored with the measurements.
+pub fn digest(entry: &Entry, context: &[(String, Vec<u8>)]) -> Result<String> {
+    let mut executables: Vec<_> = entry.executables.iter().collect();
+    executables.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut fields = context.to_vec();
+    for executable in executables {
+        let contents = Oid::hash_file(ObjectType::Blob, &executable.path)
+            .chain_err(|| format!("failed to read `{}`", executable.path.display()))?;
+        fields.push((format!("executable {}", executable.name), contents.as_bytes().to_vec()));
+    }
+    Ok(stable_hash(&fields)?.to_string())
+}
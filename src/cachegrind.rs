@@ -0,0 +1,166 @@
+use errors::*;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+lazy_static! {
+    // Example (from an `iai` harness):
+    // bench_fibonacci_short
+    //   Instructions:                1735
+    //   L1 Accesses:                 2364 (+0.042%)
+    static ref IAI_RE: Regex = Regex::new(
+        r"^\s+(Instructions|L1 Accesses|L2 Accesses|RAM Accesses|Estimated Cycles):\s+([0-9]+)").unwrap();
+}
+
+/// Deterministic counts for a single benchmark, as simulated by
+/// cachegrind.
+#[derive(Clone, Debug, Default)]
+pub struct Counts {
+    pub instructions: u64,
+    pub l1_misses: u64, // instruction and data, reads and writes
+    pub ll_misses: u64, // misses in the last-level cache
+    pub estimated_cycles: u64,
+}
+
+impl Counts {
+    /// The counts as `(metric, value)` pairs, as stored in the data
+    /// file.
+    pub fn metrics(&self) -> Vec<(&'static str, u64)> {
+        vec![("instructions", self.instructions),
+             ("l1-misses", self.l1_misses),
+             ("ll-misses", self.ll_misses),
+             ("estimated-cycles", self.estimated_cycles)]
+    }
+}
+
+/// Estimate the cycles taken by `accesses` memory accesses (including
+/// instruction fetches), given how many missed each cache level. This
+/// is the same model that `iai` uses.
+fn estimate_cycles(accesses: u64, l1_misses: u64, ll_misses: u64) -> u64 {
+    let ram_hits = ll_misses;
+    let ll_hits = l1_misses.saturating_sub(ll_misses);
+    let l1_hits = accesses.saturating_sub(l1_misses);
+    l1_hits + 5 * ll_hits + 35 * ram_hits
+}
+
+/// Read the totals from a `cachegrind.out` file, which has an
+/// `events:` line naming the counters and a `summary:` line with
+/// their totals.
+pub fn read_counts(path: &Path) -> Result<Counts> {
+    let file = File::open(path).chain_err(|| format!("failed to open `{}`", path.display()))?;
+    let mut events = vec![];
+    let mut totals = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.chain_err(|| format!("failed to read `{}`", path.display()))?;
+        if let Some(names) = line.strip_prefix("events:") {
+            events = names.split_whitespace().map(|e| e.to_string()).collect();
+        } else if let Some(summary) = line.strip_prefix("summary:") {
+            for (event, total) in events.iter().zip(summary.split_whitespace()) {
+                let total: u64 = total.parse()
+                    .chain_err(|| format!("invalid count `{}` in `{}`", total, path.display()))?;
+                totals.insert(event.clone(), total);
+            }
+        }
+    }
+    if totals.is_empty() {
+        throw!("no summary found in `{}`", path.display());
+    }
+
+    let count = |events: &[&str]| events.iter().map(|&e| totals.get(e).cloned().unwrap_or(0)).sum();
+    let instructions = count(&["Ir"]);
+    let accesses = count(&["Ir", "Dr", "Dw"]);
+    let l1_misses = count(&["I1mr", "D1mr", "D1mw"]);
+    let ll_misses = count(&["ILmr", "DLmr", "DLmw"]);
+    Ok(Counts {
+        instructions: instructions,
+        l1_misses: l1_misses,
+        ll_misses: ll_misses,
+        estimated_cycles: estimate_cycles(accesses, l1_misses, ll_misses),
+    })
+}
+
+/// The names of the benchmarks in the output of `--list` from a
+/// libtest harness (lines like `nbody::bench::nbody_par: bench`).
+pub fn parse_list(output: &str) -> Vec<String> {
+    output.lines()
+        .filter(|line| line.ends_with(": bench"))
+        .map(|line| line[..line.len() - ": bench".len()].to_string())
+        .collect()
+}
+
+/// The results printed by an `iai` harness (which runs itself under
+/// cachegrind), for each benchmark in turn.
+pub fn parse_iai(output: &str) -> Vec<(String, Counts)> {
+    let mut results: Vec<(String, Counts)> = vec![];
+    let mut name = None;
+    for line in output.lines() {
+        if let Some(captures) = IAI_RE.captures(line) {
+            let name = match name {
+                Some(ref name) => name,
+                None => continue,
+            };
+            if results.last().map(|r| &r.0) != Some(name) {
+                results.push((name.clone(), Counts::default()));
+            }
+            let counts = &mut results.last_mut().unwrap().1;
+            let value: u64 = captures[2].parse().unwrap_or(0);
+            match &captures[1] {
+                "Instructions" => counts.instructions = value,
+                "L2 Accesses" => counts.l1_misses += value,
+                "RAM Accesses" => {
+                    counts.l1_misses += value;
+                    counts.ll_misses = value;
+                }
+                "Estimated Cycles" => counts.estimated_cycles = value,
+                _ => {}
+            }
+        } else if !line.is_empty() && !line.starts_with(char::is_whitespace) {
+            name = Some(line.trim().to_string());
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_list() {
+        let output = "nbody::bench::nbody_par: bench\n\
+                      nbody::tests::energy: test\n\
+                      parse_large: bench\n\
+                      \n\
+                      2 benchmarks\n";
+        assert_eq!(parse_list(output), vec!["nbody::bench::nbody_par", "parse_large"]);
+    }
+
+    #[test]
+    fn parses_iai() {
+        let output = "bench_fibonacci_short\n\
+                      \x20 Instructions:                1735\n\
+                      \x20 L1 Accesses:                 2364 (+0.042%)\n\
+                      \x20 L2 Accesses:                    1\n\
+                      \x20 RAM Accesses:                   3\n\
+                      \x20 Estimated Cycles:            2474\n\
+                      \n\
+                      bench_fibonacci_long\n\
+                      \x20 Instructions:            26214735 (No change)\n";
+        let results = parse_iai(output);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "bench_fibonacci_short");
+        assert_eq!(results[0].1.instructions, 1735);
+        assert_eq!(results[0].1.l1_misses, 4);
+        assert_eq!(results[0].1.ll_misses, 3);
+        assert_eq!(results[0].1.estimated_cycles, 2474);
+        assert_eq!(results[1].0, "bench_fibonacci_long");
+        assert_eq!(results[1].1.instructions, 26214735);
+    }
+
+    #[test]
+    fn ignores_output_that_is_not_iai() {
+        assert!(parse_iai("running 1 test\ntest a ... bench: 10 ns/iter (+/- 1)\n").is_empty());
+    }
+}
@@ -0,0 +1,54 @@
+use errors::*;
+use sched::Sched;
+use std::env;
+use std::hint;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+/// How many times the workload is timed; the median is reported.
+const ROUNDS: usize = 21;
+
+/// A fixed workload (a mix of arithmetic, memory traffic and
+/// branches), so that its time only changes when the machine does.
+fn workload() -> u64 {
+    let mut x: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut data = vec![0u64; 4096];
+    for _ in 0..32 {
+        for d in data.iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            *d = x;
+        }
+        data.sort_unstable();
+    }
+    data.iter().fold(0, |a, &b| a ^ b)
+}
+
+/// Time the workload, printing the result the way libtest does (so
+/// that `bench` can parse it like any other benchmark).
+pub fn calibrate() -> Result<()> {
+    let mut times: Vec<u64> = (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            hint::black_box(workload());
+            let elapsed = start.elapsed();
+            elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64
+        })
+        .collect();
+    times.sort();
+    println!("test calibration ... bench: {} ns/iter (+/- {})",
+             times[ROUNDS / 2],
+             times[ROUNDS - 1] - times[0]);
+    Ok(())
+}
+
+/// A command running `cargo-chrono calibrate`, with the same CPUs and
+/// priority as the benchmarks.
+pub fn command(sched: &Sched) -> Result<Command> {
+    let exe = env::current_exe().chain_err(|| "failed to find the `cargo-chrono` executable")?;
+    let mut command = Command::new(exe);
+    command.arg("calibrate").stdin(Stdio::null()).stderr(Stdio::null());
+    sched.apply(&mut command);
+    Ok(command)
+}
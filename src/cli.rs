@@ -1,8 +1,12 @@
 use errors::*;
 use bench;
+use calibrate;
 use docopt::Docopt;
+use doctor;
 use env_logger;
+use noise;
 use plot;
+use pr;
 use std::env;
 use std::process;
 
@@ -11,22 +15,162 @@ Execute `cargo bench`, recording the results for later analysis.
 
 Usage:
     cargo-chrono bench [options] [--] [<bench-option>...]
+    cargo-chrono build-bench [options] [--] [<bench-option>...]
+    cargo-chrono noise [options] [--] [<bench-option>...]
+    cargo-chrono pr [options] [--] [<bench-option>...]
     cargo-chrono plot [options] [<plot-filter>...]
+    cargo-chrono doctor
+    cargo-chrono calibrate
     cargo-chrono --help
 
 How to use it.
 
+`build-bench` takes the same options as `bench`, but only builds each
+commit (even if a build is cached), recording how long that took as the
+`build-time` metric.
+
+`noise` measures HEAD `--launches` times (each in a separate process)
+and reports how noisy each test is: its coefficient of variation, and the
+smallest change between two runs that can be told apart from the noise
+(at `--confidence`). Tests varying by more than 5% are flagged as too
+flaky to gate on. The results are also saved next to the data file, in
+`chrono.noise.csv`.
+
+`pr` measures the merge-base of HEAD with `--base`, and HEAD (with
+`--all-commits`, every commit in between too), and prints a markdown table
+comparing the two, ready to paste into a pull request. It takes the same
+options as `bench` (except `--commits`). Changes larger than the noise
+measured by `noise` are flagged as improvements or regressions.
+
+With `--storage notes`, the measurements of each commit are kept in a git
+note on that commit, under `refs/notes/chrono` (those of the working tree
+go on the commit it is based on), so that they can be pushed and fetched
+with the repository: `git push origin refs/notes/chrono`. `plot`, `noise`
+and `pr` then read every note (including those of commits on other
+branches, or rebased away), oldest commit first. Run metadata and noise
+levels are still kept next to the data file.
+
+With `--storage dir`, each `bench` run is kept in a file of its own (with
+its metadata) in the `.chrono/` directory next to the data file. Run files
+are never changed once written, so results committed on different
+branches merge without conflicts; a run merged in twice is only loaded
+once.
+
+`doctor` checks whether the machine is quiet enough to benchmark on
+(load, CPU frequency scaling, turbo boost, battery power and other busy
+processes) and whether `cargo` and `gnuplot` are installed. `bench` runs
+the same machine checks, warns about any problems, and records what it
+found with the run.
+
+`calibrate` times a fixed workload that is part of cargo-chrono itself.
+With `bench --calibrate`, it runs before each commit is measured; use
+`plot --calibrated` to correct for the machine getting faster or slower
+between runs (or `plot --metric calibration` to see how much it did).
+
+The bench options are interpreted as `cargo bench` would: flags (and
+their values, e.g. `--features simd`) go to cargo, other arguments name
+benchmarks (each of which is measured separately), and anything after a
+second `--` goes to the bench harness, e.g.:
+
+    cargo-chrono bench -- --features simd parse -- --exact
+
+This includes `-p/--package`, `--workspace` and `--manifest-path`; the
+package and bench target of each result are recorded.
+
+The uncommitted changes (`WORKTREE`) are measured as if committed on top
+of HEAD, and labelled with HEAD plus a hash of the changes, e.g.
+`1a2b3c4+5d6e7f8`. They are stashed while other commits are checked out
+(as with `--stash`).
+
+A variant spec is a name, a colon, and a space-separated list of a
+`+toolchain`, cargo flags (features, profiles) and `KEY=VALUE` environment
+variables (used when building and running), e.g.:
+
+    cargo-chrono bench --variant 'simd: +nightly --features simd RUSTFLAGS=-Ctarget-cpu=native'
+
+Give `--variant` several times to compare several variants. Each
+measurement records its variant; use `plot --group-by variant,test`
+to compare them.
+
+The plot filters are regular expressions, matched against the commit,
+package, bench target and test name of each measurement. Prefix a filter
+with `field:` (e.g. `package:parser`) to match only that field, or with
+`!` to exclude the measurements that match.
+
 Options:
     -f, --file <file>            Data file to write to [default: chrono.csv].
+    --storage <kind>             Where to keep the measurements: `csv` (in the data file),
+                                 `notes` (in git notes on each commit) or `dir` (in a file per
+                                 run) [default: csv].
     --commits <commit-list>      (bench:) check out each commit in the (space-separated) list
-                                 in turn and run the benchmark, accumulating results
+                                 in turn and run the benchmark, accumulating results. `WORKTREE`
+                                 names the uncommitted changes, e.g. `HEAD WORKTREE`.
+    --include-worktree           (bench:) Measure the uncommitted changes too, after the commits.
     --ignore-dirty <glob> ...    (bench:) Ignore dirty files that match the given glob pattern.
-    --repeat <N>                 (bench:) Take N measurements when benchmarking [default: 1].
+    --stash                      (bench:) Stash uncommitted changes (including untracked files, but
+                                 not those matching `--ignore-dirty`) before checking out
+                                 commits, and reapply them afterwards.
+    --repeat <N>                 (bench:) Take N measurements when benchmarking, or `auto` to keep
+                                 measuring until the results are stable [default: 1].
+    --target-ci <percent>        (bench:) With `--repeat auto`, measure each benchmark until the
+                                 95% confidence interval of its median is narrower than this
+                                 [default: 2%].
+    --max-repeat <N>             (bench:) With `--repeat auto`, take at most N measurements
+                                 [default: 30].
+    --warmup <N>                 (bench:) Before measuring each benchmark at a commit, run it N times
+                                 without recording the results [default: 0].
+    --variant <spec> ...         (bench:) Build and measure each commit once per variant; see below.
+    --sweep <var=values>         (bench:) Run each benchmark once for each of the (comma-separated)
+                                 values of an environment variable, e.g. `RAYON_NUM_THREADS=1,2,4`.
+    --base <branch>              (pr:) The branch that HEAD is to be merged into [default: main].
+    --all-commits                (pr:) Measure every commit between the merge-base and HEAD too.
+    --report <file>              (pr:) Also write the markdown report to this file.
+    --launches <N>               (noise:) How many times to measure HEAD [default: 20].
+    --confidence <percent>       (noise:) Confidence with which a change must be told apart from
+                                 the noise [default: 95%].
+    --shuffle <seed>             (bench:) Run the benchmarks (and their repetitions) at each commit in
+                                 a random order; they must be named as filters. `--shuffle`
+                                 alone picks a seed; give one, as `--shuffle <seed>`, to repeat
+                                 an earlier order (the seed is recorded with the run).
+    --cpu-list <cpus>            (bench:) Pin the benchmarks (but not the builds) to these CPUs,
+                                 e.g. `4-7` or `0,2,4-6`.
+    --nice <N>                   (bench:) Run the benchmarks with this niceness, e.g. `--nice=-10`
+                                 (negative values need privileges).
+    --sizes                      (bench:) Record the size of the bench executables (`binary-size`).
+    --cachegrind                 (bench:) Run each benchmark once under `valgrind --tool=cachegrind`,
+                                 recording `instructions`, `l1-misses`, `ll-misses` and
+                                 `estimated-cycles` instead of time.
+    --calibrate                  (bench:) Time the calibration workload before measuring each commit.
+    --clean                      (build-bench:) Clean the workspace's packages (with `cargo
+                                 clean -p`) before each build, to measure clean rather than
+                                 incremental builds of them. Their dependencies, and the
+                                 cached builds, are kept.
+    --strict                     (bench:) Refuse to run if `doctor` finds the machine noisy.
+    --dry-run                    (bench:) Print the commands that would be executed, and an
+                                 estimate of how long they will take, without running them.
     --include-variance           (plot:) Include variance as errors bars.
     --medians                    (plot:) Plot medians of all samples (with error bars).
     --normalize                  (plot:) Normalize the measurements against the first commit.
                                  Implies --median.
     --output-file <file>         (plot:) Where to write the output [default: chrono.svg].
+    --group-by <fields>          (plot:) Draw one line per distinct value of these (comma-separated)
+                                 fields: `commit`, `package`, `target`, `test`, `variant` or
+                                 `param` [default: test].
+    --metric <name>              (plot:) Which metric to plot: `time` (per iteration), or one of
+                                 the resources used by each benchmark process: `max-rss`,
+                                 `user-time`, `system-time`, `voluntary-switches` or
+                                 `involuntary-switches`; or `throughput` (MB/s, for benchmarks
+                                 that set `b.bytes`); or `instructions`, `l1-misses`, `ll-misses`
+                                 or `estimated-cycles` (from `--cachegrind` or `iai` harnesses);
+                                 or `build-time` or `binary-size`.
+                                 The Y axis is scaled to a readable unit [default: time].
+    --calibrated                 (plot:) Correct times (and throughputs) for the speed of the
+                                 machine, using the calibration results of each run.
+    --sweep-x                    (plot:) Use the (numeric) value of the swept parameter as X axis.
+    --speedup                    (plot:) Plot the speedup relative to the smallest value of the
+                                 swept parameter. Implies --median.
+    --efficiency                 (plot:) Plot the speedup divided by the relative increase in the
+                                 swept parameter (i.e., parallel efficiency). Implies --median.
 ";
 
 // dead code allowed for now
@@ -34,17 +178,50 @@ Options:
 #[derive(Deserialize)]
 pub struct Args {
     cmd_bench: bool,
+    cmd_build_bench: bool,
+    cmd_noise: bool,
+    cmd_pr: bool,
     cmd_plot: bool,
+    cmd_doctor: bool,
+    cmd_calibrate: bool,
     arg_bench_option: Vec<String>,
     arg_plot_filter: Vec<String>,
     flag_file: String,
-    flag_repeat: usize,
+    flag_storage: String,
+    flag_repeat: String,
+    flag_target_ci: String,
+    flag_max_repeat: usize,
+    flag_warmup: usize,
+    flag_launches: String,
+    flag_confidence: String,
+    flag_base: String,
+    flag_all_commits: bool,
+    flag_report: Option<String>,
     flag_ignore_dirty: Vec<String>,
+    flag_stash: bool,
+    flag_include_worktree: bool,
     flag_include_variance: bool,
     flag_medians: bool,
     flag_normalize: bool,
     flag_output_file: String,
+    flag_group_by: String,
     flag_commits: Option<String>,
+    flag_variant: Vec<String>,
+    flag_sweep: Option<String>,
+    flag_shuffle: Option<String>,
+    flag_cpu_list: Option<String>,
+    flag_nice: Option<String>,
+    flag_metric: String,
+    flag_sweep_x: bool,
+    flag_speedup: bool,
+    flag_efficiency: bool,
+    flag_dry_run: bool,
+    flag_strict: bool,
+    flag_sizes: bool,
+    flag_clean: bool,
+    flag_cachegrind: bool,
+    flag_calibrate: bool,
+    flag_calibrated: bool,
 }
 
 pub fn main() {
@@ -59,32 +236,133 @@ pub fn main() {
     }
 }
 
+/// `--shuffle` takes an optional seed, which docopt cannot express, so
+/// a `--shuffle` that is not followed by a seed (a number) becomes
+/// `--shuffle=random`.
+fn expand_shuffle(args: Vec<String>) -> Vec<String> {
+    let mut argv = vec![];
+    let mut options_done = false;
+    for (i, arg) in args.iter().enumerate() {
+        options_done |= arg == "--";
+        let seeded = args.get(i + 1).is_some_and(|next| next.parse::<u64>().is_ok());
+        if arg == "--shuffle" && !options_done && !seeded {
+            argv.push("--shuffle=random".to_string());
+        } else {
+            argv.push(arg.clone());
+        }
+    }
+    argv
+}
+
 fn run() -> Result<()> {
     env_logger::init();
     debug!("env_logger initialized");
 
+    let argv = expand_shuffle(env::args().collect());
     let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.argv(env::args().into_iter()).deserialize())
+        .and_then(|d| d.argv(argv).deserialize())
         .unwrap_or_else(|e| e.exit());
 
-    if args.cmd_bench {
-        bench::bench(&args.flag_file,
-                     &args.flag_ignore_dirty,
-                     args.flag_repeat,
-                     &args.flag_commits,
-                     &args.arg_bench_option)?;
+    let no_commits = None;
+    if args.cmd_bench || args.cmd_build_bench || args.cmd_noise || args.cmd_pr {
+        let config = bench::Config {
+            ignore_dirty: &args.flag_ignore_dirty,
+            repeat: &args.flag_repeat,
+            target_ci: &args.flag_target_ci,
+            max_repeat: args.flag_max_repeat,
+            warmup: args.flag_warmup,
+            commits: &args.flag_commits,
+            bench_options: &args.arg_bench_option,
+            variants: &args.flag_variant,
+            sweep: &args.flag_sweep,
+            shuffle: &args.flag_shuffle,
+            cpu_list: &args.flag_cpu_list,
+            nice: &args.flag_nice,
+            dry_run: args.flag_dry_run,
+            strict: args.flag_strict,
+            build_only: args.cmd_build_bench,
+            clean: args.flag_clean,
+            sizes: args.flag_sizes,
+            cachegrind: args.flag_cachegrind,
+            calibrate: args.flag_calibrate,
+            stash: args.flag_stash,
+            include_worktree: args.flag_include_worktree,
+            storage: &args.flag_storage,
+        };
+        if args.cmd_noise {
+            // measure HEAD only, once per launch
+            noise::noise(&args.flag_file,
+                         bench::Config {
+                             repeat: &args.flag_launches,
+                             commits: &no_commits,
+                             ..config
+                         },
+                         &args.flag_confidence)?;
+        } else if args.cmd_pr {
+            pr::pr(&args.flag_file,
+                   config,
+                   &args.flag_base,
+                   args.flag_all_commits,
+                   &args.flag_report)?;
+        } else {
+            bench::bench(&args.flag_file, config)?;
+        }
     } else if args.cmd_plot {
         plot::plot(&args.flag_file,
                    plot::Config {
                        include_variance: args.flag_include_variance,
-                       compute_medians: args.flag_medians || args.flag_normalize,
+                       compute_medians: args.flag_medians || args.flag_normalize ||
+                                        args.flag_speedup ||
+                                        args.flag_efficiency,
                        compute_normalize: args.flag_normalize,
                        output_file: &args.flag_output_file,
                        filters: &args.arg_plot_filter,
+                       group_by: &args.flag_group_by,
+                       metric: &args.flag_metric,
+                       sweep_x: args.flag_sweep_x,
+                       compute_speedup: args.flag_speedup,
+                       compute_efficiency: args.flag_efficiency,
+                       calibrated: args.flag_calibrated,
+                       storage: &args.flag_storage,
                    })?;
+    } else if args.cmd_doctor {
+        doctor::doctor()?;
+    } else if args.cmd_calibrate {
+        calibrate::calibrate()?;
     } else {
         throw!("bug: unknown command")
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(args: &[&str]) -> Vec<String> {
+        expand_shuffle(args.iter().map(|a| a.to_string()).collect())
+    }
+
+    #[test]
+    fn shuffle_without_seed() {
+        assert_eq!(expand(&["cargo-chrono", "bench", "--shuffle"]),
+                   vec!["cargo-chrono", "bench", "--shuffle=random"]);
+        assert_eq!(expand(&["cargo-chrono", "bench", "--shuffle", "nbody"]),
+                   vec!["cargo-chrono", "bench", "--shuffle=random", "nbody"]);
+    }
+
+    #[test]
+    fn shuffle_with_seed() {
+        assert_eq!(expand(&["cargo-chrono", "bench", "--shuffle", "42", "nbody"]),
+                   vec!["cargo-chrono", "bench", "--shuffle", "42", "nbody"]);
+        assert_eq!(expand(&["cargo-chrono", "bench", "--shuffle=42"]),
+                   vec!["cargo-chrono", "bench", "--shuffle=42"]);
+    }
+
+    #[test]
+    fn shuffle_after_options() {
+        assert_eq!(expand(&["cargo-chrono", "bench", "--", "--shuffle"]),
+                   vec!["cargo-chrono", "bench", "--", "--shuffle"]);
+    }
+}
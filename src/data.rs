@@ -1,19 +1,305 @@
 use errors::*;
 use csv;
+use chrono::Local;
+use lock::Lock;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
-#[derive(Clone, Debug, RustcDecodable)]
+#[derive(Clone, Debug)]
 pub struct Measurement {
     pub commit: String, // a sha1 hash
     pub test: String, // name of test that was run
-    pub time: u64,
-    pub variance: u64,
+    pub metric: String, // what was measured, e.g. `time` (per iteration) or `max-rss`
+    pub unit: String, // e.g. `ns` or `bytes`
+    pub value: f64,
+    pub spread: Option<f64>, // e.g. the `+/-` that libtest reports
+
+    // if the bench executables of `commit` were identical to those of
+    // another commit, that commit's results were reused; this is it
+    pub alias_of: Option<String>,
+
+    // the `bench` session that produced this measurement; see `write_run`
+    pub run: Option<String>,
+
+    // the package and bench target that the test belongs to
+    pub package: Option<String>,
+    pub target: Option<String>,
+
+    // the variant (toolchain, features, etc.) it was built and run with
+    pub variant: Option<String>,
+
+    // the swept parameter, as `NAME=VALUE`
+    pub param: Option<String>,
+
+    // identifies the bench executables (and how they were run) that
+    // produced this measurement; see `bench::run_digest`
+    pub digest: Option<String>,
+}
+
+/// Identifies what was measured (as opposed to the result).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub commit: String,
+    pub package: Option<String>,
+    pub target: Option<String>,
+    pub variant: Option<String>,
+    pub param: Option<String>,
+    pub metric: String,
+    pub test: String,
+}
+
+impl Key {
+    /// The same measurement, at any commit.
+    pub fn without_commit(&self) -> Key {
+        Key { commit: String::new(), ..self.clone() }
+    }
+
+    /// The same measurement, at any value of the swept parameter.
+    pub fn without_param(&self) -> Key {
+        Key { param: None, ..self.clone() }
+    }
+}
+
+/// The unit of the metrics that cargo-chrono records itself. Data
+/// files written before units were recorded rely on this.
+pub fn default_unit(metric: &str) -> &'static str {
+    match metric {
+        "time" | "user-time" | "system-time" | "build-time" | "calibration" => "ns",
+        "max-rss" | "binary-size" => "bytes",
+        "throughput" => "MB/s",
+        _ => "count",
+    }
+}
+
+/// Whether larger values of `metric` are better (most metrics are
+/// costs, so smaller is better).
+pub fn higher_is_better(metric: &str) -> bool {
+    metric == "throughput"
+}
+
+/// The metrics of the benchmarks themselves, which `noise` and `pr`
+/// look at (the resource usage of the bench processes is not something
+/// to gate on).
+pub const BENCH_METRICS: &'static [&'static str] = &["time", "throughput", "instructions"];
+
+impl Measurement {
+    /// A measurement of `metric` (in its default unit) with no
+    /// spread; fill in the rest with `..`.
+    pub fn new(commit: &str, test: &str, metric: &str, value: f64) -> Measurement {
+        Measurement {
+            commit: commit.to_string(),
+            test: test.to_string(),
+            metric: metric.to_string(),
+            unit: default_unit(metric).to_string(),
+            value: value,
+            spread: None,
+            alias_of: None,
+            run: None,
+            package: None,
+            target: None,
+            variant: None,
+            param: None,
+            digest: None,
+        }
+    }
+
+    /// The name and numeric value of the swept parameter, if any.
+    pub fn param_value(&self) -> Option<(&str, f64)> {
+        let param = self.param.as_ref()?;
+        let i = param.find('=')?;
+        param[i + 1..].parse().ok().map(|value| (&param[..i], value))
+    }
+
+    pub fn key(&self) -> Key {
+        Key {
+            commit: self.commit.clone(),
+            package: self.package.clone(),
+            target: self.target.clone(),
+            variant: self.variant.clone(),
+            param: self.param.clone(),
+            metric: self.metric.clone(),
+            test: self.test.clone(),
+        }
+    }
+
+    /// Decode one row of the data file. Older data files lack the
+    /// trailing columns, so those are optional.
+    fn from_record(record: &[String]) -> Result<Measurement> {
+        if record.len() < 4 {
+            bail!("expected at least 4 fields, found {}", record.len());
+        }
+        let parse = |s: &str| -> Result<f64> {
+            s.trim().parse().chain_err(|| format!("invalid number `{}`", s))
+        };
+        let optional = |i: usize| record.get(i).cloned().filter(|s| !s.is_empty());
+        let metric = optional(10).unwrap_or_else(|| "time".to_string());
+        let unit = optional(11).unwrap_or_else(|| default_unit(&metric).to_string());
+        Ok(Measurement {
+            commit: record[0].clone(),
+            test: record[1].clone(),
+            value: parse(&record[2])?,
+            spread: match optional(3) {
+                Some(s) => Some(parse(&s)?),
+                None => None,
+            },
+            alias_of: optional(4),
+            run: optional(5),
+            package: optional(6),
+            target: optional(7),
+            variant: optional(8),
+            param: optional(9),
+            metric: metric,
+            unit: unit,
+            digest: optional(12),
+        })
+    }
+
+    fn to_record(&self) -> Vec<String> {
+        let optional = |s: &Option<String>| s.clone().unwrap_or_default();
+        vec![self.commit.clone(),
+             self.test.clone(),
+             self.value.to_string(),
+             self.spread.map(|s| s.to_string()).unwrap_or_default(),
+             optional(&self.alias_of),
+             optional(&self.run),
+             optional(&self.package),
+             optional(&self.target),
+             optional(&self.variant),
+             optional(&self.param),
+             self.metric.clone(),
+             self.unit.clone(),
+             optional(&self.digest)]
+    }
 }
 
 pub fn load_measurements(path: &str) -> Result<Vec<Measurement>> {
-    let mut reader = csv::Reader::from_file(&path)
-        .chain_err(|| format!("cannot read `{}`", path))?;
+    let file = File::open(path).chain_err(|| format!("cannot read `{}`", path))?;
+    let mut bytes = vec![];
+    {
+        let _lock = Lock::shared(&file).chain_err(|| format!("failed to lock `{}`", path))?;
+        (&file).read_to_end(&mut bytes).chain_err(|| format!("cannot read `{}`", path))?;
+    }
+
+    // Every record ends in a newline, so one that does not was cut
+    // short (by a crash, say) while being written.
+    let complete = bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    if complete < bytes.len() {
+        println_err!("warning: skipping partial record at the end of `{}`: `{}`",
+                     path,
+                     String::from_utf8_lossy(&bytes[complete..]));
+        bytes.truncate(complete);
+    }
+    decode_measurements(csv::Reader::from_bytes(bytes))
+}
+
+/// Decode measurements written (by `write_measurement`) elsewhere than
+/// the data file, e.g. to a git note.
+pub fn parse_measurements(text: &str) -> Result<Vec<Measurement>> {
+    decode_measurements(csv::Reader::from_string(text))
+}
 
-    reader.decode()
+fn decode_measurements<R: Read>(reader: csv::Reader<R>) -> Result<Vec<Measurement>> {
+    let mut reader = reader.has_headers(false).flexible(true);
+    reader.records()
           .map(|r| r.chain_err(|| format!("cannot decode CSV data")))
+          .map(|r| r.and_then(|r| Measurement::from_record(&r)))
           .collect()
 }
+
+/// Load a run file from `runs_dir`: the metadata of the run (as written
+/// by `write_run`, three fields per row), then its measurements. Also
+/// returns the id of the run.
+pub fn load_run(path: &Path) -> Result<(Option<String>, Vec<Measurement>)> {
+    let mut reader = csv::Reader::from_file(path)
+        .chain_err(|| format!("cannot read `{}`", path.display()))?
+        .has_headers(false)
+        .flexible(true);
+
+    let mut run = None;
+    let mut measurements = vec![];
+    for record in reader.records() {
+        let record = record.chain_err(|| "cannot decode CSV data")?;
+        if record.len() == 3 {
+            run = Some(record[0].clone());
+        } else {
+            let m = Measurement::from_record(&record)?;
+            run = run.or_else(|| m.run.clone());
+            measurements.push(m);
+        }
+    }
+    Ok((run, measurements))
+}
+
+pub fn write_measurement<W: Write>(writer: &mut csv::Writer<W>, m: &Measurement) -> Result<()> {
+    writer.write(m.to_record().into_iter())
+          .chain_err(|| format!("failed to write data for test `{}`", m.test))
+}
+
+/// Metadata about each `bench` session (which arguments it was given
+/// and so forth) is kept next to the data file, e.g. `chrono.csv` has
+/// `chrono.runs.csv`. Each row is `(run, key, value)`.
+pub fn runs_path(data_path: &Path) -> PathBuf {
+    data_path.with_extension("runs.csv")
+}
+
+/// With `dir` storage, each `bench` session is kept in a file of its
+/// own in this directory instead, e.g. `.chrono/20240101-120000-1234.csv`
+/// (see `load_run`).
+pub fn runs_dir(data_path: &Path) -> PathBuf {
+    data_path.with_file_name(".chrono")
+}
+
+/// Where `noise` keeps the noise level of each test.
+pub fn noise_path(data_path: &Path) -> PathBuf {
+    data_path.with_extension("noise.csv")
+}
+
+/// A fresh identifier for a `bench` session.
+pub fn new_run_id() -> String {
+    format!("{}-{}", Local::now().format("%Y%m%d-%H%M%S"), process::id())
+}
+
+pub fn write_run<W: Write>(writer: &mut csv::Writer<W>,
+                          run: &str,
+                          metadata: &[(&str, String)])
+                          -> Result<()> {
+    for &(key, ref value) in metadata {
+        writer.encode((run, key, value))
+              .chain_err(|| format!("failed to write run metadata `{}`", key))?;
+    }
+    writer.flush().chain_err(|| "failed to write run metadata")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// A file of its own for each test, holding `contents`.
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("cargo-chrono-{}-{}.csv", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn skips_a_partial_record() {
+        let path = temp_file("partial", b"abc,nbody,100,5\nabc,parse,2");
+        let measurements = load_measurements(&path.to_string_lossy()).unwrap();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].test, "nbody");
+        assert_eq!(measurements[0].value, 100.0);
+        assert_eq!(measurements[0].spread, Some(5.0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skips_a_file_without_any_newline() {
+        let path = temp_file("no-complete-record", b"abc,nbody,100,5");
+        assert!(load_measurements(&path.to_string_lossy()).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
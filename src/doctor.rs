@@ -0,0 +1,262 @@
+use errors::*;
+use std::collections::HashMap;
+use std::fs;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Processes using more than this fraction of a CPU are reported as
+/// competing with the benchmarks.
+const BUSY_PROCESS: f64 = 0.1;
+
+/// A load average above this fraction of the CPUs online is reported
+/// as a busy machine.
+const BUSY_LOAD: f64 = 0.5;
+
+/// One observation about the machine.
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str, // recorded in the run metadata
+    pub value: String, // what we found ("unknown" if we cannot tell)
+    pub problem: Option<String>, // why this may make the results unreliable
+}
+
+impl Check {
+    fn new(name: &'static str, value: String, problem: Option<String>) -> Check {
+        Check {
+            name: name,
+            value: value,
+            problem: problem,
+        }
+    }
+
+    fn unknown(name: &'static str) -> Check {
+        Check::new(name, "unknown".to_string(), None)
+    }
+}
+
+/// Print the result of every check.
+pub fn doctor() -> Result<()> {
+    let mut problems = 0;
+    for check in machine_checks().into_iter().chain(tool_checks()) {
+        match check.problem {
+            Some(ref problem) => {
+                problems += 1;
+                println!("{:<10} {} (warning: {})", check.name, check.value, problem);
+            }
+            None => println!("{:<10} {}", check.name, check.value),
+        }
+    }
+    if problems == 0 {
+        println!("no problems found");
+    } else {
+        println!("{} problem(s) found", problems);
+    }
+    Ok(())
+}
+
+/// Check for things that make benchmark results noisy.
+pub fn machine_checks() -> Vec<Check> {
+    vec![check_load(), check_governor(), check_turbo(), check_power(), check_processes()]
+}
+
+/// Check that the tools we run are installed.
+pub fn tool_checks() -> Vec<Check> {
+    vec![check_tool("cargo", "cargo", "needed to build the benchmarks"),
+         check_tool("gnuplot", "gnuplot", "needed by `plot`")]
+}
+
+fn read(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn check_load() -> Check {
+    let loadavg = match read("/proc/loadavg") {
+        Some(l) => l,
+        None => return Check::unknown("load"),
+    };
+    let load: f64 = match loadavg.split_whitespace().next().and_then(|l| l.parse().ok()) {
+        Some(l) => l,
+        None => return Check::unknown("load"),
+    };
+    let cpus = online_cpus().unwrap_or(1);
+    let problem = if load >= BUSY_LOAD * cpus as f64 {
+        Some(format!("the load average over the last minute is {} (with {} CPUs online)",
+                     load,
+                     cpus))
+    } else {
+        None
+    };
+    Check::new("load", load.to_string(), problem)
+}
+
+fn check_governor() -> Check {
+    let mut governors = vec![];
+    if let Ok(cpus) = fs::read_dir("/sys/devices/system/cpu") {
+        for cpu in cpus.filter_map(|c| c.ok()) {
+            let path = cpu.path().join("cpufreq/scaling_governor");
+            if let Some(governor) = read(&path.to_string_lossy()) {
+                governors.push(governor);
+            }
+        }
+    }
+    governors.sort();
+    governors.dedup();
+    if governors.is_empty() {
+        return Check::unknown("governor");
+    }
+    let problem = if governors.iter().any(|g| g != "performance") {
+        Some("CPU frequency scaling is enabled; use the `performance` governor".to_string())
+    } else {
+        None
+    };
+    Check::new("governor", governors.join(","), problem)
+}
+
+fn check_turbo() -> Check {
+    // `no_turbo` for intel_pstate, `boost` for acpi-cpufreq
+    let enabled = match read("/sys/devices/system/cpu/intel_pstate/no_turbo") {
+        Some(no_turbo) => no_turbo == "0",
+        None => {
+            match read("/sys/devices/system/cpu/cpufreq/boost") {
+                Some(boost) => boost == "1",
+                None => return Check::unknown("turbo"),
+            }
+        }
+    };
+    if enabled {
+        Check::new("turbo",
+                   "on".to_string(),
+                   Some("turbo boost is enabled, so the clock speed varies".to_string()))
+    } else {
+        Check::new("turbo", "off".to_string(), None)
+    }
+}
+
+fn check_power() -> Check {
+    let supplies = match fs::read_dir("/sys/class/power_supply") {
+        Ok(s) => s,
+        Err(_) => return Check::unknown("power"),
+    };
+    for supply in supplies.filter_map(|s| s.ok()) {
+        let path = supply.path();
+        let field = |name: &str| read(&path.join(name).to_string_lossy()).unwrap_or_default();
+        if field("type") == "Battery" && field("status") == "Discharging" {
+            return Check::new("power",
+                              "battery".to_string(),
+                              Some("the machine is running on battery".to_string()));
+        }
+    }
+    Check::new("power", "ac".to_string(), None)
+}
+
+/// Look for other processes using a lot of CPU, by sampling their CPU
+/// time twice.
+fn check_processes() -> Check {
+    let (ticks_per_sec, before) = match (clock_ticks(), cpu_times()) {
+        (Some(t), Some(before)) => (t, before),
+        _ => return Check::unknown("processes"),
+    };
+    let interval = Duration::from_millis(250);
+    thread::sleep(interval);
+    let after = match cpu_times() {
+        Some(a) => a,
+        None => return Check::unknown("processes"),
+    };
+
+    let budget = ticks_per_sec as f64 * interval.subsec_nanos() as f64 / 1_000_000_000.0;
+    let mut busy: Vec<_> = after.iter()
+        .filter_map(|(pid, &(ref name, ticks))| {
+            let earlier = before.get(pid).map(|b| b.1).unwrap_or(ticks);
+            let usage = ticks.saturating_sub(earlier) as f64 / budget;
+            if usage > BUSY_PROCESS {
+                Some(format!("{} ({}, {:.0}%)", name, pid, usage * 100.0))
+            } else {
+                None
+            }
+        })
+        .collect();
+    busy.sort();
+    if busy.is_empty() {
+        Check::new("processes", "idle".to_string(), None)
+    } else {
+        let list = busy.join(", ");
+        Check::new("processes",
+                   list.clone(),
+                   Some(format!("other processes are using the CPU: {}", list)))
+    }
+}
+
+/// The name and CPU time (in clock ticks) of every other process.
+fn cpu_times() -> Option<HashMap<u32, (String, u64)>> {
+    let own_pid = process::id();
+    let mut times = HashMap::new();
+    for entry in fs::read_dir("/proc").ok()?.filter_map(|e| e.ok()) {
+        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(pid) if pid != own_pid => pid,
+            _ => continue,
+        };
+        // `pid (name) state ...`, where the name may contain spaces
+        let stat = match read(&entry.path().join("stat").to_string_lossy()) {
+            Some(s) => s,
+            None => continue,
+        };
+        let (open, close) = match (stat.find('('), stat.rfind(')')) {
+            (Some(open), Some(close)) if open < close => (open, close),
+            _ => continue,
+        };
+        let fields: Vec<_> = stat[close + 1..].split_whitespace().collect();
+        // utime and stime are the 14th and 15th fields overall
+        let ticks = match (fields.get(11).and_then(|t| t.parse::<u64>().ok()),
+                           fields.get(12).and_then(|t| t.parse::<u64>().ok())) {
+            (Some(utime), Some(stime)) => utime + stime,
+            _ => continue,
+        };
+        times.insert(pid, (stat[open + 1..close].to_string(), ticks));
+    }
+    Some(times)
+}
+
+#[cfg(unix)]
+fn clock_ticks() -> Option<u64> {
+    use libc;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks > 0 { Some(ticks as u64) } else { None }
+}
+
+#[cfg(not(unix))]
+fn clock_ticks() -> Option<u64> {
+    None
+}
+
+/// The number of CPUs online.
+#[cfg(unix)]
+pub fn online_cpus() -> Option<usize> {
+    use libc;
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus > 0 { Some(cpus as usize) } else { None }
+}
+
+#[cfg(not(unix))]
+pub fn online_cpus() -> Option<usize> {
+    None
+}
+
+fn check_tool(name: &'static str, program: &str, purpose: &str) -> Check {
+    let output = Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(ref output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            Check::new(name, version.lines().next().unwrap_or("").to_string(), None)
+        }
+        _ => {
+            Check::new(name,
+                       "missing".to_string(),
+                       Some(format!("`{}` was not found; it is {}", program, purpose)))
+        }
+    }
+}
@@ -1,6 +1,9 @@
+use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use git2::{Commit, Object, Repository, Status};
+use std::process::Command;
+use git2::{Commit, Index, IndexEntry, IndexTime, Object, Oid, Repository, Signature, Status,
+           Time};
 use git2::build::CheckoutBuilder;
 use errors::*;
 
@@ -10,7 +13,10 @@ pub fn open_repo(start_path: &Path) -> Result<Repository> {
        .chain_err(|| format!("could not find git repository in `{}`", start_path.display()))?)
 }
 
-pub fn check_clean(repo: &Repository, exceptions: &[PathBuf]) -> Result<()> {
+/// The dirty files (including untracked ones), relative to the top of
+/// the work tree, other than `exceptions` (and the files in them, for
+/// directories).
+pub fn dirty_files(repo: &Repository, exceptions: &[PathBuf]) -> Result<Vec<String>> {
     let statuses = repo.statuses(None)
         .chain_err(|| "could not load git repository status")?;
 
@@ -20,35 +26,245 @@ pub fn check_clean(repo: &Repository, exceptions: &[PathBuf]) -> Result<()> {
         })
         .collect());
 
-    let workdir = match repo.workdir() {
-        Some(w) => w,
-        None => throw!("bare repositories are not supported"),
-    };
-    let mut errors = 0;
+    let workdir = workdir(repo)?;
+    let mut dirty = vec![];
     let dirty_status = Status::all() - Status::IGNORED;
     for status in statuses.iter() {
         if status.status().intersects(dirty_status) {
-            let stderr = io::stderr();
-            let mut stderr = stderr.lock();
-            if let Some(path_str) = status.path() {
-                let path = workdir.join(path_str)
-                    .canonicalize()
+            let path_str = match status.path() {
+                Some(p) => p,
+                None => throw!("path of dirty file not utf-8"),
+            };
+            // (deleted files cannot be exceptions)
+            let path = workdir.join(path_str);
+            if path.exists() {
+                let path = path.canonicalize()
                     .chain_err(|| format!("failed to canonicalize `{}`", path_str))?;
-                if exceptions.iter().any(|e| *e == path) {
+                if exceptions.iter().any(|e| path.starts_with(e)) {
                     continue;
                 }
-                writeln!(stderr, "file `{}` is dirty", path_str).unwrap();
             }
-            errors += 1;
+            dirty.push(path_str.to_string());
         }
     }
-    if errors > 0 {
-        throw!(ErrorKind::DirtyRepo(errors))
+    Ok(dirty)
+}
+
+pub fn check_clean(repo: &Repository, exceptions: &[PathBuf]) -> Result<()> {
+    let dirty = dirty_files(repo, exceptions)?;
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    for path in &dirty {
+        writeln!(stderr, "file `{}` is dirty", path).unwrap();
+    }
+    if !dirty.is_empty() {
+        throw!(ErrorKind::DirtyRepo(dirty.len()))
+    }
+
+    Ok(())
+}
+
+fn workdir(repo: &Repository) -> Result<&Path> {
+    match repo.workdir() {
+        Some(w) => Ok(w),
+        None => throw!("bare repositories are not supported"),
+    }
+}
+
+/// Run `git` in the work tree, returning its output (or an error
+/// including what it printed).
+fn git(repo: &Repository, args: &[String]) -> Result<String> {
+    let mut command = Command::new("git");
+    command.current_dir(workdir(repo)?).args(args);
+    let output = command.output().chain_err(|| "error executing `git`")?;
+    if !output.status.success() {
+        bail!("`{:?}` failed: {}", command, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The commit that `refs/stash` points to, if any.
+fn stash_top(repo: &Repository) -> Option<Oid> {
+    repo.refname_to_id("refs/stash").ok()
+}
+
+/// Stash the dirty files (including untracked ones), except for
+/// `exceptions` (like the data file, which is being written to).
+/// Returns the stash commit, if there was anything to stash.
+pub fn stash(repo: &Repository, exceptions: &[PathBuf]) -> Result<Option<Oid>> {
+    let dirty = dirty_files(repo, exceptions)?;
+    if dirty.is_empty() {
+        return Ok(None);
+    }
+    let mut args: Vec<String> = vec!["stash".into(),
+                                     "push".into(),
+                                     "--include-untracked".into(),
+                                     "--message".into(),
+                                     "cargo-chrono: changes stashed while benchmarking".into(),
+                                     "--".into()];
+    args.extend(dirty.iter().map(|path| format!(":(top,literal){}", path)));
+
+    let before = stash_top(repo);
+    git(repo, &args).chain_err(|| "failed to stash the dirty files")?;
+    let after = stash_top(repo);
+    Ok(if after != before { after } else { None })
+}
+
+/// Reapply the changes stashed by `stash` (staged changes staged
+/// again, if possible), and drop the stash. If they do not apply
+/// cleanly, the stash is kept, so nothing is lost.
+pub fn unstash(repo: &Repository, stash: Oid) -> Result<()> {
+    let apply = |index: bool| {
+        let mut args: Vec<String> = vec!["stash".into(), "apply".into()];
+        if index {
+            args.push("--index".into());
+        }
+        args.push(stash.to_string());
+        git(repo, &args)
+    };
+    let applied = apply(true).or_else(|e| {
+        println_err!("warning: could not restage your staged changes ({}); reapplying them \
+                      unstaged",
+                     e);
+        apply(false)
+    });
+    if let Err(e) = applied {
+        bail!("failed to reapply your stashed changes ({}); they are kept in stash `{}` \
+               (see `git stash list`)",
+              e,
+              stash);
     }
 
+    // Find the stash again, in case other stashes were made since.
+    let reflog = repo.reflog("refs/stash").chain_err(|| "failed to read the stash list")?;
+    let index = match reflog.iter().position(|entry| entry.id_new() == stash) {
+        Some(i) => i,
+        None => throw!("stash `{}` was applied, but is no longer in the stash list", stash),
+    };
+    git(repo, &["stash".into(), "drop".into(), format!("stash@{{{}}}", index)])
+        .chain_err(|| {
+            format!("stashed changes were reapplied, but stash `{}` was not dropped", stash)
+        })?;
     Ok(())
 }
 
+/// The revision naming the working tree, uncommitted changes and all.
+pub const WORKTREE: &'static str = "WORKTREE";
+
+/// The message of the pseudo-commits made by `worktree_commit`; their
+/// label follows.
+const WORKTREE_MESSAGE: &'static str = "cargo-chrono: uncommitted changes of ";
+
+/// Make a commit (on top of `head`, but not on any branch) of the
+/// working tree, including untracked files but not `exceptions`, so
+/// that it can be checked out and measured like any other commit.
+/// Returns `None` if there is nothing to commit.
+///
+/// The commit is labelled with `head` plus a hash of the changes, and
+/// has a fixed author and date, so the same changes give the same
+/// commit (and reuse its cached build).
+pub fn worktree_commit<'r>(repo: &'r Repository,
+                           head: &Commit<'r>,
+                           exceptions: &[PathBuf])
+                           -> Result<Option<Commit<'r>>> {
+    let dirty = dirty_files(repo, exceptions)?;
+    if dirty.is_empty() {
+        return Ok(None);
+    }
+
+    let workdir = workdir(repo)?;
+    let head_tree = head.tree().chain_err(|| "failed to read the tree of HEAD")?;
+    let mut index = Index::new().chain_err(|| "failed to create an index")?;
+    index.read_tree(&head_tree).chain_err(|| "failed to read the tree of HEAD")?;
+    for path in &dirty {
+        let full_path = workdir.join(path);
+        let metadata = match fs::symlink_metadata(&full_path) {
+            Ok(m) => m,
+            Err(_) => {
+                // deleted
+                index.remove_path(Path::new(path))
+                    .chain_err(|| format!("failed to remove `{}` from the index", path))?;
+                continue;
+            }
+        };
+        let id = if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&full_path)
+                .chain_err(|| format!("failed to read link `{}`", path))?;
+            repo.blob(target.to_string_lossy().as_bytes())
+        } else {
+            repo.blob_path(&full_path)
+        };
+        let id = id.chain_err(|| format!("failed to store `{}`", path))?;
+        index.add(&IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: file_mode(&metadata),
+                uid: 0,
+                gid: 0,
+                file_size: metadata.len() as u32,
+                id: id,
+                flags: 0,
+                flags_extended: 0,
+                path: path.as_bytes().to_vec(),
+            })
+            .chain_err(|| format!("failed to add `{}` to the index", path))?;
+    }
+    let tree_id = index.write_tree_to(repo).chain_err(|| "failed to write the working tree")?;
+    let tree = repo.find_tree(tree_id).chain_err(|| "failed to write the working tree")?;
+
+    let diff = repo.diff_tree_to_tree(Some(&head_tree), Some(&tree), None)
+        .chain_err(|| "failed to diff the working tree against HEAD")?;
+    let patch_id = diff.patchid(None).chain_err(|| "failed to hash the uncommitted changes")?;
+    let label = format!("{}+{}", short_id(head), &patch_id.to_string()[..7]);
+
+    let signature = Signature::new("cargo-chrono", "cargo-chrono", &Time::new(0, 0))
+        .chain_err(|| "failed to create a signature")?;
+    let id = repo.commit(None,
+                &signature,
+                &signature,
+                &format!("{}{}", WORKTREE_MESSAGE, label),
+                &tree,
+                &[head])
+        .chain_err(|| "failed to commit the working tree")?;
+    let commit = repo.find_commit(id).chain_err(|| "failed to commit the working tree")?;
+    Ok(Some(commit))
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    if metadata.file_type().is_symlink() {
+        0o120000
+    } else if metadata.permissions().mode() & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.file_type().is_symlink() { 0o120000 } else { 0o100644 }
+}
+
+/// How `commit` is labelled in the data file: its short id or, for the
+/// working tree, the commit it is based on plus a hash of the changes.
+pub fn label(commit: &Commit) -> String {
+    if is_worktree(commit) {
+        commit.message().unwrap_or_default()[WORKTREE_MESSAGE.len()..].to_string()
+    } else {
+        short_id(commit)
+    }
+}
+
+/// Whether `commit` was made by `worktree_commit`.
+pub fn is_worktree(commit: &Commit) -> bool {
+    commit.message().is_some_and(|m| m.starts_with(WORKTREE_MESSAGE))
+}
+
 pub trait AsObject<'repo> {
     fn as_object(&self) -> &Object<'repo>;
 }
@@ -87,3 +303,117 @@ pub fn checkout_commit(repo: &Repository, commit: &Commit)
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::StatusOptions;
+    use std::env;
+    use std::process;
+
+    /// A repository of its own for each test, with `a.txt` committed.
+    fn temp_repo(name: &str) -> (PathBuf, Repository) {
+        let dir = env::temp_dir().join(format!("cargo-chrono-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let repo = Repository::init(&dir).unwrap();
+        {
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+        }
+        fs::write(dir.join("a.txt"), "one\n").unwrap();
+        commit_all(&repo, "one");
+        (dir, repo)
+    }
+
+    fn commit_all(repo: &Repository, message: &str) -> Oid {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents).unwrap()
+    }
+
+    fn status(repo: &Repository, path: &str) -> Status {
+        let mut options = StatusOptions::new();
+        options.include_untracked(true);
+        let statuses = repo.statuses(Some(&mut options)).unwrap();
+        let status = statuses.iter().find(|s| s.path() == Some(path)).map(|s| s.status());
+        status.unwrap_or(Status::CURRENT)
+    }
+
+    #[test]
+    fn nothing_to_stash() {
+        let (dir, repo) = temp_repo("stash-clean");
+        assert_eq!(stash(&repo, &[]).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stash_and_unstash() {
+        let (dir, repo) = temp_repo("stash");
+        fs::write(dir.join("a.txt"), "two\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a.txt")).unwrap();
+        index.write().unwrap();
+        fs::write(dir.join("b.txt"), "untracked\n").unwrap();
+        fs::write(dir.join("data.csv"), "kept\n").unwrap();
+        let exceptions = vec![dir.join("data.csv")];
+
+        let stashed = stash(&repo, &exceptions).unwrap().unwrap();
+        assert!(dirty_files(&repo, &exceptions).unwrap().is_empty());
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "one\n");
+        assert!(!dir.join("b.txt").exists());
+        assert_eq!(fs::read_to_string(dir.join("data.csv")).unwrap(), "kept\n");
+
+        unstash(&repo, stashed).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "two\n");
+        assert_eq!(status(&repo, "a.txt"), Status::INDEX_MODIFIED);
+        assert_eq!(status(&repo, "b.txt"), Status::WT_NEW);
+        assert!(stash_top(&repo).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nothing_to_commit() {
+        let (dir, repo) = temp_repo("worktree-clean");
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert!(worktree_commit(&repo, &head, &[]).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn commit_the_worktree() {
+        let (dir, repo) = temp_repo("worktree");
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        fs::write(dir.join("a.txt"), "two\n").unwrap();
+        fs::write(dir.join("b.txt"), "untracked\n").unwrap();
+        fs::write(dir.join("data.csv"), "kept\n").unwrap();
+        let exceptions = vec![dir.join("data.csv")];
+
+        let commit = worktree_commit(&repo, &head, &exceptions).unwrap().unwrap();
+        assert!(is_worktree(&commit));
+        assert!(!is_worktree(&head));
+        assert_eq!(commit.parent_ids().collect::<Vec<_>>(), vec![head.id()]);
+        assert!(label(&commit).starts_with(&format!("{}+", short_id(&head))));
+
+        let tree = commit.tree().unwrap();
+        let blob = tree.get_path(Path::new("a.txt")).unwrap().to_object(&repo).unwrap();
+        assert_eq!(blob.as_blob().unwrap().content(), b"two\n");
+        assert!(tree.get_path(Path::new("b.txt")).is_ok());
+        assert!(tree.get_path(Path::new("data.csv")).is_err());
+
+        // Neither HEAD nor the working tree is touched, and the same
+        // changes give the same commit.
+        assert_eq!(repo.head().unwrap().target(), Some(head.id()));
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "two\n");
+        let again = worktree_commit(&repo, &head, &exceptions).unwrap().unwrap();
+        assert_eq!(again.id(), commit.id());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -9,6 +9,7 @@ extern crate git2;
 extern crate gnuplot;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate regex;
@@ -22,10 +23,22 @@ extern crate serde_derive;
 mod macros;
 
 mod bench;
+mod bench_args;
+mod cache;
+mod cachegrind;
+mod calibrate;
 mod cli;
 mod data;
+mod doctor;
 mod errors;
 mod git;
+mod lock;
+mod noise;
 mod plot;
+mod pr;
+mod rusage;
+mod sched;
+mod store;
+mod variant;
 
 pub use cli::main;
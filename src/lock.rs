@@ -0,0 +1,60 @@
+use std::fs::File;
+use std::io;
+
+/// An advisory lock on a file (see `flock(2)`), released when dropped.
+/// Every cargo-chrono process takes it before touching the data file,
+/// so that concurrent `bench` sessions do not interleave their rows, and
+/// readers never see half of a write.
+pub struct Lock<'f> {
+    file: &'f File,
+}
+
+impl<'f> Lock<'f> {
+    /// Lock `file` to write to it, waiting for anyone else using it.
+    pub fn exclusive(file: &'f File) -> io::Result<Lock<'f>> {
+        Lock::new(file, true)
+    }
+
+    /// Lock `file` to read it, waiting for anyone writing to it.
+    pub fn shared(file: &'f File) -> io::Result<Lock<'f>> {
+        Lock::new(file, false)
+    }
+
+    #[cfg(unix)]
+    fn new(file: &'f File, exclusive: bool) -> io::Result<Lock<'f>> {
+        use libc;
+        use std::os::unix::io::AsRawFd;
+
+        let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(Lock { file: file });
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new(file: &'f File, _exclusive: bool) -> io::Result<Lock<'f>> {
+        // Not supported; concurrent sessions are up to the user.
+        Ok(Lock { file: file })
+    }
+}
+
+impl<'f> Drop for Lock<'f> {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        use libc;
+        use std::os::unix::io::AsRawFd;
+
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
@@ -1,13 +1,16 @@
 macro_rules! throw {
     ($e:expr) => {
-        return Err($e.into());
+        return Err($e.into())
     };
     ($fmt:expr, $($arg:tt)+) => {
-        return Err(format!($fmt, $($arg)+).into());
+        return Err(format!($fmt, $($arg)+).into())
     };
 }
 
 macro_rules! println_err {
+    ($fmt:expr) => {
+        println_err!("{}", $fmt)
+    };
     ($fmt:expr, $($arg:tt)+) => {
         {
             use std::io;
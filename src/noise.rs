@@ -0,0 +1,210 @@
+use bench;
+use csv;
+use data::{self, Key, Measurement};
+use errors::*;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use store::{self, Storage};
+
+/// Benchmarks whose coefficient of variation is above this are too
+/// flaky to gate on.
+const FLAKY_CV: f64 = 0.05;
+
+/// The noise of one test, as measured by `noise`.
+#[derive(Clone, Debug)]
+pub struct Noise {
+    pub key: Key, // without a commit
+    pub samples: usize,
+    pub cv: f64, // coefficient of variation: standard deviation / mean
+    pub detectable: f64, // smallest relative change distinguishable from noise
+}
+
+impl Noise {
+    pub fn is_flaky(&self) -> bool {
+        self.cv > FLAKY_CV
+    }
+}
+
+/// Measure HEAD over and over (as set up in `config`), then report how
+/// noisy each test is and save the results.
+pub fn noise(data_file: &str, config: bench::Config, confidence: &str) -> Result<()> {
+    let z = z_score(confidence)?;
+    let storage = Storage::parse(config.storage)?;
+    let run_id = match bench::bench(data_file, config)? {
+        Some(run_id) => run_id,
+        None => return Ok(()), // dry run
+    };
+
+    let measurements: Vec<_> = store::load_measurements(data_file, storage)?
+        .into_iter()
+        .filter(|m| m.run.as_ref() == Some(&run_id) && data::BENCH_METRICS.contains(&&m.metric[..]))
+        .collect();
+    if measurements.is_empty() {
+        throw!("no benchmark results to analyze");
+    }
+    let noise = compute_noise(&measurements, z);
+
+    println!();
+    println!("{:<40} {:<12} {:>7} {:>7} {:>11}",
+             "test",
+             "metric",
+             "samples",
+             "cv",
+             "detectable");
+    for n in &noise {
+        println!("{:<40} {:<12} {:>7} {:>6.1}% {:>10.1}%{}",
+                 describe(&n.key),
+                 n.key.metric,
+                 n.samples,
+                 n.cv * 100.0,
+                 n.detectable * 100.0,
+                 if n.is_flaky() { "  (too flaky to gate on)" } else { "" });
+    }
+    println!("(detectable: the smallest change between two runs that is not noise, with {} \
+              confidence)",
+             confidence);
+
+    let path = data::noise_path(Path::new(data_file));
+    let file = store::Output::append(&path)
+        .chain_err(|| format!("failed to open noise file `{}`", path.display()))?;
+    write_noise(&mut csv::Writer::from_writer(file), &run_id, &noise)?;
+    println!("noise levels saved to `{}`", path.display());
+    Ok(())
+}
+
+/// The target, variant, parameter and name of a test.
+pub fn describe(key: &Key) -> String {
+    let parts: Vec<_> = [&key.target, &key.variant, &key.param]
+        .iter()
+        .filter_map(|p| p.as_ref())
+        .map(|p| &p[..])
+        .chain(Some(&key.test[..]))
+        .collect();
+    parts.join(" ")
+}
+
+/// The coefficient of variation of each test, and from it the smallest
+/// change between two single runs that can be told apart from noise.
+fn compute_noise(measurements: &[Measurement], z: f64) -> Vec<Noise> {
+    let mut keys = vec![];
+    let mut values = HashMap::new();
+    for m in measurements {
+        let key = m.key().without_commit();
+        values.entry(key.clone())
+            .or_insert_with(|| {
+                keys.push(key);
+                vec![]
+            })
+            .push(m.value);
+    }
+
+    keys.into_iter()
+        .map(|key| {
+            let values = &values[&key];
+            let n = values.len() as f64;
+            let mean = values.iter().sum::<f64>() / n;
+            let variance = if values.len() > 1 {
+                values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / (n - 1.0)
+            } else {
+                0.0
+            };
+            let cv = if mean != 0.0 { variance.sqrt() / mean.abs() } else { 0.0 };
+            Noise {
+                key: key,
+                samples: values.len(),
+                cv: cv,
+                detectable: z * 2f64.sqrt() * cv,
+            }
+        })
+        .collect()
+}
+
+fn write_noise<W: Write>(writer: &mut csv::Writer<W>, run: &str, noise: &[Noise]) -> Result<()> {
+    for n in noise {
+        let opt = |s: &Option<String>| s.clone().unwrap_or_default();
+        writer.encode((run,
+                     opt(&n.key.package),
+                     opt(&n.key.target),
+                     opt(&n.key.variant),
+                     opt(&n.key.param),
+                     &n.key.test,
+                     &n.key.metric,
+                     n.samples,
+                     n.cv,
+                     n.detectable))
+            .chain_err(|| format!("failed to write noise of `{}`", n.key.test))?;
+    }
+    writer.flush().chain_err(|| "failed to write noise levels")
+}
+
+/// The noise levels saved by `noise` next to `data_file`, oldest first
+/// (none if `noise` was never run).
+pub fn load_noise(data_file: &str) -> Result<Vec<Noise>> {
+    let path = data::noise_path(Path::new(data_file));
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut reader = csv::Reader::from_file(&path)
+        .chain_err(|| format!("cannot read `{}`", path.display()))?
+        .has_headers(false);
+
+    let mut noise = vec![];
+    for record in reader.records() {
+        let record = record.chain_err(|| "cannot decode CSV data")?;
+        if record.len() != 10 {
+            bail!("expected 10 fields in `{}`, found {}", path.display(), record.len());
+        }
+        let optional = |i: usize| Some(record[i].clone()).filter(|s| !s.is_empty());
+        let parse = |i: usize| -> Result<f64> {
+            record[i].parse().chain_err(|| format!("invalid number `{}`", record[i]))
+        };
+        noise.push(Noise {
+            key: Key {
+                commit: String::new(),
+                package: optional(1),
+                target: optional(2),
+                variant: optional(3),
+                param: optional(4),
+                test: record[5].clone(),
+                metric: record[6].clone(),
+            },
+            samples: parse(7)? as usize,
+            cv: parse(8)?,
+            detectable: parse(9)?,
+        });
+    }
+    Ok(noise)
+}
+
+/// The z-score for a two-sided confidence level like `95%`.
+fn z_score(confidence: &str) -> Result<f64> {
+    let level: f64 = match confidence.trim_end_matches('%').parse() {
+        Ok(l) if l > 0.0 && l < 100.0 => l,
+        _ => throw!("`--confidence` should be a percentage, like `95%`, not `{}`", confidence),
+    };
+    // Abramowitz and Stegun 26.2.23, good to about 5e-4.
+    let p = (1.0 - level / 100.0) / 2.0;
+    let t = (-2.0 * p.ln()).sqrt();
+    Ok(t - (2.515517 + 0.802853 * t + 0.010328 * t * t) /
+           (1.0 + 1.432788 * t + 0.189269 * t * t + 0.001308 * t * t * t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn z_scores() {
+        assert!((z_score("95%").unwrap() - 1.960).abs() < 1e-3);
+        assert!((z_score("99").unwrap() - 2.576).abs() < 1e-3);
+        assert!((z_score("80%").unwrap() - 1.282).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rejects_bad_confidence() {
+        assert!(z_score("100%").is_err());
+        assert!(z_score("0").is_err());
+        assert!(z_score("high").is_err());
+    }
+}
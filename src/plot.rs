@@ -2,8 +2,9 @@ use data::{self, Measurement};
 use errors::*;
 use gnuplot::{AutoOption, AxesCommon, Figure, PlotOption, Tick};
 use regex::Regex;
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
-use std::cmp;
+use store::{self, Storage};
 
 pub struct Config<'c> {
     pub include_variance: bool,
@@ -11,10 +12,89 @@ pub struct Config<'c> {
     pub compute_normalize: bool,
     pub output_file: &'c str,
     pub filters: &'c [String],
+    pub group_by: &'c str,
+    pub metric: &'c str,
+    pub sweep_x: bool,
+    pub compute_speedup: bool,
+    pub compute_efficiency: bool,
+    pub calibrated: bool, // correct for machine speed, using the calibration runs
+    pub storage: &'c str, // where the measurements are; see `store::Storage`
+}
+
+/// The properties of a measurement that filters and data sets can
+/// key on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Field {
+    Commit,
+    Package,
+    Target,
+    Test,
+    Variant,
+    Param,
+}
+
+impl Field {
+    fn from_str(s: &str) -> Option<Field> {
+        match s {
+            "commit" => Some(Field::Commit),
+            "package" => Some(Field::Package),
+            "target" => Some(Field::Target),
+            "test" => Some(Field::Test),
+            "variant" => Some(Field::Variant),
+            "param" => Some(Field::Param),
+            _ => None,
+        }
+    }
+
+    fn value(self, m: &Measurement) -> &str {
+        let value = match self {
+            Field::Commit => Some(&m.commit),
+            Field::Package => m.package.as_ref(),
+            Field::Target => m.target.as_ref(),
+            Field::Test => Some(&m.test),
+            Field::Variant => m.variant.as_ref(),
+            Field::Param => m.param.as_ref(),
+        };
+        value.map(|s| &s[..]).unwrap_or("")
+    }
+}
+
+/// A filter is a regular expression, optionally prefixed with `!` to
+/// invert it and with `field:` to match only that field (otherwise,
+/// it matches if any field does).
+struct Filter {
+    inverted: bool,
+    field: Option<Field>,
+    regex: Regex,
+}
+
+impl Filter {
+    fn is_match(&self, m: &Measurement) -> bool {
+        match self.field {
+            Some(field) => self.regex.is_match(field.value(m)),
+            None => {
+                [Field::Commit,
+                 Field::Package,
+                 Field::Target,
+                 Field::Test,
+                 Field::Variant,
+                 Field::Param]
+                    .iter()
+                    .any(|&field| self.regex.is_match(field.value(m)))
+            }
+        }
+    }
 }
 
 pub fn plot(data_file: &str, mut config: Config) -> Result<()> {
-    let mut measurements = data::load_measurements(data_file)?;
+    let mut measurements = store::load_measurements(data_file, Storage::parse(config.storage)?)?;
+
+    // The calibration results must be gathered before filtering.
+    let calibrations = if config.calibrated {
+        Some(compute_calibrations(&measurements)?)
+    } else {
+        None
+    };
 
     // First apply the filters.
     let filters: Vec<_> = try!(config.filters
@@ -25,19 +105,63 @@ pub fn plot(data_file: &str, mut config: Config) -> Result<()> {
             } else {
                 (false, &f[..])
             };
+            let (field, text) = match text.find(':') {
+                Some(i) if Field::from_str(&text[..i]).is_some() => {
+                    (Field::from_str(&text[..i]), &text[i + 1..])
+                }
+                _ => (None, text),
+            };
             Regex::new(text)
                 .chain_err(|| format!("filter `{}` not a valid regular expression", f))
-                .map(|r| (inverted, r))
+                .map(|r| {
+                    Filter {
+                        inverted: inverted,
+                        field: field,
+                        regex: r,
+                    }
+                })
         })
         .collect());
     measurements.retain(|m| passes_filters(&filters, m));
 
+    // Only one metric can be plotted at a time.
+    measurements.retain(|m| m.metric == config.metric);
+    if measurements.is_empty() {
+        throw!("no `{}` measurements to plot", config.metric);
+    }
+
+    // ...and its measurements must all be in the same unit.
+    let mut units: Vec<_> = measurements.iter().map(|m| &m.unit[..]).collect();
+    units.sort();
+    units.dedup();
+    if units.len() > 1 {
+        throw!("`{}` is measured in incompatible units ({}); use filters to pick one",
+               config.metric,
+               units.join(", "));
+    }
+
+    if let Some(ref calibrations) = calibrations {
+        measurements = apply_calibrations(&measurements, calibrations)?;
+    }
+
     // Convert to medians
     if config.compute_medians {
         measurements = compute_medians(&measurements, config.compute_normalize);
         config.include_variance = true;
     }
 
+    // If there is a swept parameter, it can be used as a (numeric) X
+    // axis, and the speedup computed relative to its smallest value.
+    if config.compute_speedup || config.compute_efficiency {
+        measurements = compute_speedups(&measurements, config.compute_efficiency);
+        if measurements.is_empty() {
+            throw!("`--speedup`/`--efficiency` need results from a `--sweep`");
+        }
+    }
+    if config.sweep_x {
+        return plot_params_as_x(&measurements, config);
+    }
+
     // If there are multiple commits, then we want to use each commit as a point
     // on the X axis.
     if measurements[1..].iter().any(|m| m.commit != measurements[0].commit) {
@@ -54,12 +178,11 @@ pub fn plot(data_file: &str, mut config: Config) -> Result<()> {
     return plot_indices_as_x(&measurements, config);
 }
 
-fn passes_filters(filters: &[(bool, Regex)], m: &Measurement) -> bool {
+fn passes_filters(filters: &[Filter], m: &Measurement) -> bool {
     if filters.is_empty() {
         true
     } else {
-        filters.iter()
-            .any(|&(inverted, ref r)| !inverted == (r.is_match(&m.commit) || r.is_match(&m.test)))
+        filters.iter().any(|f| f.inverted != f.is_match(m))
     }
 }
 
@@ -73,13 +196,22 @@ fn plot_tests_as_x(measurements: &[Measurement], config: Config) -> Result<()> {
     plot_with_x_axis(measurements, &x_axis, config)
 }
 
+fn plot_params_as_x(measurements: &[Measurement], config: Config) -> Result<()> {
+    let ref x_axis = compute_x_axis_from_params(measurements)?;
+    plot_with_x_axis(measurements, &x_axis, config)
+}
+
 fn plot_indices_as_x(measurements: &[Measurement], config: Config) -> Result<()> {
     let ref x_axis = compute_x_axis_from_indices(measurements);
     plot_with_x_axis(measurements, &x_axis, config)
 }
 
 fn plot_with_x_axis(measurements: &[Measurement], x_axis: &XAxis, config: Config) -> Result<()> {
-    let ref data_sets = compute_data_sets(measurements);
+    let group_by: Vec<_> = try!(config.group_by
+        .split(',')
+        .map(|f| Field::from_str(f.trim()).ok_or_else(|| format!("cannot group by `{}`", f)))
+        .collect());
+    let ref data_sets = compute_data_sets(measurements, &group_by);
 
     let mut fg = Figure::new();
 
@@ -88,22 +220,30 @@ fn plot_with_x_axis(measurements: &[Measurement], x_axis: &XAxis, config: Config
         axes.set_x_axis(true, &[]);
         axes.set_x_label(&x_axis.axis_label, &[]);
         axes.set_y_axis(true, &[]);
-        if !config.compute_normalize {
-            axes.set_y_label("ns/iter", &[]);
+        let max = measurements.iter().fold(0.0, |max: f64, m| max.max(m.value.abs()));
+        let (unit, factor) = scale_unit(&measurements[0].unit, max);
+        if config.compute_efficiency {
+            axes.set_y_label("parallel efficiency (%)", &[]);
+        } else if config.compute_speedup {
+            axes.set_y_label("speedup (%)", &[]);
+        } else if !config.compute_normalize {
+            axes.set_y_label(&format!("{} ({})", config.metric, unit), &[]);
         } else {
-            axes.set_y_label("normalized ns/iter", &[]);
+            let better = if data::higher_is_better(config.metric) { "higher" } else { "lower" };
+            axes.set_y_label(&format!("normalized {} (%, {} is better)", config.metric, better),
+                             &[]);
         }
 
         if let Some(ref ticks) = x_axis.ticks {
             let gnu_ticks = ticks.iter()
                 .enumerate()
-                .map(|(i, s)| Tick::Major(i, AutoOption::Fix(s.to_string())));
+                .map(|(i, s)| Tick::Major(i as f64, AutoOption::Fix(s.to_string())));
             axes.set_x_ticks_custom(gnu_ticks, &[], &[]);
         }
 
         for (ds_name, ds_measurements) in data_sets {
             let xs = ds_measurements.iter().map(|&i| x_axis.coords[i]);
-            let ys = ds_measurements.iter().map(|&i| measurements[i].time);
+            let ys = ds_measurements.iter().map(|&i| measurements[i].value / factor);
 
             let name = escape(&ds_name);
             let options = vec![PlotOption::Caption(&name)];
@@ -113,7 +253,8 @@ fn plot_with_x_axis(measurements: &[Measurement], x_axis: &XAxis, config: Config
                 // cargo bench reports the diff between max/min. That
                 // means we want a bar of equal height on top and
                 // bottom, so divide by 2.
-                let y_errors = ds_measurements.iter().map(|&i| measurements[i].variance);
+                let y_errors = ds_measurements.iter()
+                    .map(|&i| measurements[i].spread.unwrap_or(0.0) / factor);
                 axes.y_error_lines(xs, ys, y_errors, &options);
             }
         }
@@ -131,7 +272,7 @@ struct XAxis {
     axis_label: String,
 
     // for each measurement, what is its x coordinate?
-    coords: Vec<usize>,
+    coords: Vec<f64>,
 
     // how to label each x coordinate? (if None, use numbers)
     ticks: Option<Vec<String>>,
@@ -141,7 +282,21 @@ struct XAxis {
 /// commits first appear in the order desired. The user can sort if
 /// that is not the case.
 fn compute_x_axis_from_commits(measurements: &[Measurement]) -> XAxis {
-    compute_x_axis_from_names(measurements, "commit", |m| &m.commit)
+    let mut x_axis = compute_x_axis_from_names(measurements, "commit", |m| &m.commit);
+
+    // Mark the commits whose results were reused from another commit.
+    if let Some(ref mut ticks) = x_axis.ticks {
+        for (m, &coord) in measurements.iter().zip(&x_axis.coords) {
+            let index = coord as usize;
+            if let Some(ref original) = m.alias_of {
+                if ticks[index] == m.commit {
+                    ticks[index] = format!("{} (= {})", m.commit, original);
+                }
+            }
+        }
+    }
+
+    x_axis
 }
 
 /// Compute the X axis based on the test name. We assume that the
@@ -167,7 +322,7 @@ fn compute_x_axis_from_names<F>(measurements: &[Measurement], axis_label: &str,
         ticks[index] = commit.clone();
     }
 
-    let coords: Vec<_> = measurements.iter().map(|m| map[name(m)]).collect();
+    let coords: Vec<_> = measurements.iter().map(|m| map[name(m)] as f64).collect();
 
     XAxis {
         axis_label: axis_label.to_string(),
@@ -179,19 +334,126 @@ fn compute_x_axis_from_names<F>(measurements: &[Measurement], axis_label: &str,
 fn compute_x_axis_from_indices(measurements: &[Measurement]) -> XAxis {
     XAxis {
         axis_label: "measurement".to_string(),
-        coords: (0..measurements.len()).collect(),
+        coords: (0..measurements.len()).map(|i| i as f64).collect(),
         ticks: None,
     }
 }
 
-fn compute_data_sets(measurements: &[Measurement]) -> HashMap<String, Vec<usize>> {
+/// Compute the X axis from the value of the swept parameter, which
+/// must be numeric.
+fn compute_x_axis_from_params(measurements: &[Measurement]) -> Result<XAxis> {
+    let mut axis_label = None;
+    let mut coords = vec![];
+    for m in measurements {
+        match m.param_value() {
+            Some((name, value)) => {
+                axis_label = Some(name.to_string());
+                coords.push(value);
+            }
+            None => throw!("test `{}` at `{}` has no numeric parameter", m.test, m.commit),
+        }
+    }
+
+    Ok(XAxis {
+        axis_label: axis_label.unwrap_or_default(),
+        coords: coords,
+        ticks: None,
+    })
+}
+
+fn compute_data_sets(measurements: &[Measurement],
+                     group_by: &[Field])
+                     -> HashMap<String, Vec<usize>> {
     let mut result = HashMap::new();
     for (i, m) in measurements.iter().enumerate() {
-        result.entry(m.test.clone()).or_insert(vec![]).push(i);
+        let names: Vec<_> = group_by.iter()
+            .map(|&field| field.value(m))
+            .filter(|name| !name.is_empty())
+            .collect();
+        result.entry(names.join("/")).or_insert(vec![]).push(i);
     }
     result
 }
 
+/// Pick a unit in which values up to `max` (given in `unit`) read
+/// well, e.g. ms rather than ns; returns it, and the factor to divide
+/// the values by.
+pub fn scale_unit(unit: &str, max: f64) -> (String, f64) {
+    let steps: &[(&str, f64)] = match unit {
+        "ns" => &[("ns", 1.0), ("µs", 1e3), ("ms", 1e6), ("s", 1e9)],
+        "bytes" => &[("bytes", 1.0),
+                     ("KiB", 1024.0),
+                     ("MiB", 1024.0 * 1024.0),
+                     ("GiB", 1024.0 * 1024.0 * 1024.0)],
+        _ => return (unit.to_string(), 1.0),
+    };
+    let &(name, factor) = steps.iter()
+        .rev()
+        .find(|&&(_, factor)| max >= factor)
+        .unwrap_or(&steps[0]);
+    (name.to_string(), factor)
+}
+
+/// The run, commit and variant that a calibration result applies to.
+type CalibrationKey = (Option<String>, String, Option<String>);
+
+/// For each calibration result, how much slower (> 1) or faster (< 1)
+/// the machine was than its median over all calibration results.
+fn compute_calibrations(measurements: &[Measurement]) -> Result<HashMap<CalibrationKey, f64>> {
+    let calibrations: Vec<_> = measurements.iter().filter(|m| m.metric == "calibration").collect();
+    if calibrations.is_empty() {
+        throw!("no calibration results found; run `bench --calibrate` to record them");
+    }
+    let mut values: Vec<_> = calibrations.iter().map(|m| m.value).collect();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let (reference, _) = compute_median_and_error(&values);
+    Ok(calibrations.iter()
+        .filter(|m| m.value > 0.0)
+        .map(|m| ((m.run.clone(), m.commit.clone(), m.variant.clone()), m.value / reference))
+        .collect())
+}
+
+/// Correct the measurements for the speed of the machine at the time.
+/// Measurements without a calibration result are dropped.
+fn apply_calibrations(measurements: &[Measurement],
+                      calibrations: &HashMap<CalibrationKey, f64>)
+                      -> Result<Vec<Measurement>> {
+    let mut result = vec![];
+    let mut uncalibrated = 0;
+    for m in measurements {
+        let key = (m.run.clone(), m.commit.clone(), m.variant.clone());
+        let factor = match calibrations.get(&key) {
+            Some(&factor) => factor,
+            None => {
+                uncalibrated += 1;
+                continue;
+            }
+        };
+        // a slow machine takes longer, and gets through less data
+        let factor = match &m.unit[..] {
+            "ns" => 1.0 / factor,
+            "MB/s" => factor,
+            unit => {
+                throw!("cannot calibrate `{}`, which is not measured in time ({})",
+                       m.metric,
+                       unit)
+            }
+        };
+        result.push(Measurement {
+            value: m.value * factor,
+            spread: m.spread.map(|spread| spread * factor),
+            ..m.clone()
+        });
+    }
+    if uncalibrated > 0 {
+        println_err!("warning: ignoring {} measurements without calibration results", uncalibrated);
+    }
+    if result.is_empty() {
+        throw!("no calibrated measurements to plot");
+    }
+    Ok(result)
+}
+
 fn escape(name: &str) -> String {
     // GNU plot converts `_` into subscript; I can't find a way to
     // disable this escaping in the Rust wrapper so...
@@ -201,19 +463,28 @@ fn escape(name: &str) -> String {
 fn compute_medians(measurements: &[Measurement], normalize: bool) -> Vec<Measurement> {
     let mut keys = vec![];
     let mut map = HashMap::new();
+    let mut templates = HashMap::new();
     for measurement in measurements {
-        let key = (measurement.commit.clone(), measurement.test.clone());
+        let key = measurement.key();
         let aggregated = map.entry(key.clone())
             .or_insert_with(|| {
-                keys.push(key);
+                keys.push(key.clone());
                 vec![]
             });
-        aggregated.push(measurement.time);
+        aggregated.push(measurement.value);
+
+        // the medians inherit everything else from the first
+        // measurement with a given key
+        templates.entry(key)
+            .or_insert_with(|| Measurement {
+                run: None, // may combine several runs
+                ..measurement.clone()
+            });
     }
 
     // sort the values
     for (_key, values) in &mut map {
-        values.sort();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
     }
 
     if !normalize {
@@ -221,10 +492,9 @@ fn compute_medians(measurements: &[Measurement], normalize: bool) -> Vec<Measure
             .map(|key| {
                 let (median, error) = compute_median_and_error(&map[&key]);
                 Measurement {
-                    commit: key.0,
-                    test: key.1,
-                    time: median,
-                    variance: error,
+                    value: median,
+                    spread: Some(error),
+                    ..templates[&key].clone()
                 }
             })
             .collect();
@@ -233,7 +503,7 @@ fn compute_medians(measurements: &[Measurement], normalize: bool) -> Vec<Measure
     let mut baselines = HashMap::new();
     for key in &keys {
         // for each test, take the first commit we found
-        baselines.entry(key.1.clone())
+        baselines.entry(key.without_commit())
             .or_insert_with(|| {
                 let (median, _error) = compute_median_and_error(&map[key]);
                 median
@@ -242,53 +512,190 @@ fn compute_medians(measurements: &[Measurement], normalize: bool) -> Vec<Measure
 
     keys.into_iter()
         .map(|key| {
-            let baseline = baselines[&key.1];
+            let baseline = baselines[&key.without_commit()];
             let (median, error) = compute_median_and_error(&map[&key]);
             Measurement {
-                commit: key.0,
-                test: key.1,
-                time: scale(median, baseline),
-                variance: scale(error, baseline),
+                value: scale(median, baseline),
+                spread: Some(scale(error, baseline)),
+                unit: "%".to_string(),
+                ..templates[&key].clone()
             }
         })
         .collect()
 }
 
-/// Input: sorted list of u64.
+/// Replace each value with the speedup relative to the same test at
+/// the smallest value of the swept parameter (as a percentage). If
+/// `efficiency` is true, also divide by the relative increase in the
+/// parameter (e.g., the number of threads). For metrics where higher
+/// is better (e.g. throughput), the speedup is the ratio the other way
+/// around. Measurements without a numeric parameter are dropped.
+fn compute_speedups(measurements: &[Measurement], efficiency: bool) -> Vec<Measurement> {
+    let mut baselines = HashMap::new();
+    for m in measurements {
+        if let Some((_, value)) = m.param_value() {
+            let baseline = baselines.entry(m.key().without_param()).or_insert((value, m.value));
+            if value < baseline.0 {
+                *baseline = (value, m.value);
+            }
+        }
+    }
+
+    measurements.iter()
+        .filter_map(|m| {
+            let value = match m.param_value() {
+                Some((_, value)) => value,
+                None => return None,
+            };
+            let (baseline_value, baseline) = baselines[&m.key().without_param()];
+            let measured = if m.value > 0.0 { m.value } else { 1.0 };
+            let mut speedup = if data::higher_is_better(&m.metric) {
+                measured / if baseline > 0.0 { baseline } else { 1.0 }
+            } else {
+                baseline / measured
+            };
+            if efficiency && value != 0.0 {
+                speedup *= baseline_value / value;
+            }
+            Some(Measurement {
+                value: speedup * 100.0,
+                spread: m.spread.map(|spread| speedup * 100.0 * spread / measured),
+                unit: "%".to_string(),
+                ..m.clone()
+            })
+        })
+        .collect()
+}
+
+/// Input: sorted list of values.
 /// Output: median and maximum error
-fn compute_median_and_error(values: &[u64]) -> (u64, u64) {
+pub fn compute_median_and_error(values: &[f64]) -> (f64, f64) {
     let len = values.len();
     let median = if len == 0 {
-        0
+        0.0
     } else if len % 2 == 1 {
         // odd number. pick the one in the middle.
         //
-        // [0, 1, 2, 3]
-        values[len / 2 - 1] / 2 + values[len / 2] / 2
+        // [0, 1, 2]
+        values[len / 2]
     } else {
         // even number: average the two in the middle.
         //
-        // [0, 1, 2]
-        values[len / 2]
+        // [0, 1, 2, 3]
+        (values[len / 2 - 1] + values[len / 2]) / 2.0
     };
 
     let error = if len == 0 {
-        0
+        0.0
     } else {
-        cmp::max(median - values[0], values[len - 1] - median)
+        (median - values[0]).max(values[len - 1] - median)
     };
 
     (median, error)
 }
 
-fn scale(value: u64, mut baseline: u64) -> u64 {
-    if baseline == 0 {
+fn scale(value: f64, mut baseline: f64) -> f64 {
+    if baseline == 0.0 {
         // Should basically never happen. It would mean test took 0ns to
         // run.
-        baseline = 1;
+        baseline = 1.0;
+    }
+    (value / baseline) * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(param: &str, metric: &str, value: f64) -> Measurement {
+        Measurement {
+            param: Some(param.to_string()),
+            spread: Some(value / 10.0),
+            ..Measurement::new("abc", "nbody", metric, value)
+        }
+    }
+
+    fn values(measurements: &[Measurement]) -> Vec<f64> {
+        measurements.iter().map(|m| m.value).collect()
+    }
+
+    #[test]
+    fn speedups_relative_to_the_smallest_param() {
+        let measurements = vec![at("N=2", "time", 50.0),
+                                at("N=1", "time", 100.0),
+                                at("N=4", "time", 40.0),
+                                Measurement::new("abc", "nbody", "time", 10.0)];
+        let speedups = compute_speedups(&measurements, false);
+        assert_eq!(values(&speedups), vec![200.0, 100.0, 250.0]);
+        assert!(speedups.iter().all(|m| m.unit == "%"));
+        assert_eq!(speedups[0].spread, Some(20.0));
+
+        let efficiencies = compute_speedups(&measurements, true);
+        assert_eq!(values(&efficiencies), vec![100.0, 100.0, 62.5]);
+    }
+
+    #[test]
+    fn speedups_of_throughput() {
+        let measurements = vec![at("N=1", "throughput", 100.0), at("N=2", "throughput", 150.0)];
+        assert_eq!(values(&compute_speedups(&measurements, false)), vec![100.0, 150.0]);
+    }
+
+    #[test]
+    fn no_speedups_without_params() {
+        let measurements = vec![Measurement::new("abc", "nbody", "time", 10.0)];
+        assert!(compute_speedups(&measurements, false).is_empty());
+    }
+
+    #[test]
+    fn median_and_error() {
+        assert_eq!(compute_median_and_error(&[]), (0.0, 0.0));
+        assert_eq!(compute_median_and_error(&[4.0]), (4.0, 0.0));
+        assert_eq!(compute_median_and_error(&[1.0, 2.0, 6.0]), (2.0, 4.0));
+        assert_eq!(compute_median_and_error(&[1.0, 2.0, 3.0, 10.0]), (2.5, 7.5));
+    }
+
+    fn calibration(commit: &str, value: f64) -> Measurement {
+        Measurement {
+            run: Some("run".to_string()),
+            ..Measurement::new(commit, "calibration", "calibration", value)
+        }
+    }
+
+    #[test]
+    fn calibrations_relative_to_their_median() {
+        let measurements = vec![calibration("a", 100.0),
+                                calibration("b", 200.0),
+                                calibration("c", 50.0),
+                                Measurement::new("a", "nbody", "time", 10.0)];
+        let calibrations = compute_calibrations(&measurements).unwrap();
+        assert_eq!(calibrations.len(), 3);
+        assert_eq!(calibrations[&(Some("run".to_string()), "b".to_string(), None)], 2.0);
+        assert_eq!(calibrations[&(Some("run".to_string()), "c".to_string(), None)], 0.5);
+        assert!(compute_calibrations(&measurements[3..]).is_err());
+    }
+
+    #[test]
+    fn calibrations_correct_time_and_throughput() {
+        let mut calibrations = HashMap::new();
+        calibrations.insert((Some("run".to_string()), "b".to_string(), None), 2.0);
+        let measured = |metric, value| {
+            Measurement {
+                run: Some("run".to_string()),
+                spread: Some(10.0),
+                ..Measurement::new("b", "nbody", metric, value)
+            }
+        };
+
+        let corrected = apply_calibrations(&[measured("time", 100.0),
+                                             measured("throughput", 30.0),
+                                             Measurement::new("b", "nbody", "time", 1.0)],
+                                           &calibrations)
+            .unwrap();
+        assert_eq!(values(&corrected), vec![50.0, 60.0]);
+        assert_eq!(corrected[0].spread, Some(5.0));
+
+        assert!(apply_calibrations(&[measured("max-rss", 100.0)], &calibrations).is_err());
+        assert!(apply_calibrations(&[Measurement::new("b", "nbody", "time", 1.0)], &calibrations)
+            .is_err());
     }
-    let value = value as f64;
-    let baseline = baseline as f64;
-    let percent = (value / baseline) * 100.0;
-    percent as u64
 }
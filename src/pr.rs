@@ -0,0 +1,167 @@
+use bench;
+use data::{self, Key, Measurement};
+use errors::*;
+use git;
+use git2::Sort;
+use noise;
+use plot;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use store::{self, Storage};
+
+/// Benchmark the merge-base of HEAD and `base`, and HEAD (and, with
+/// `all_commits`, every commit in between), then print a markdown
+/// report comparing the two.
+pub fn pr(data_file: &str,
+          config: bench::Config,
+          base: &str,
+          all_commits: bool,
+          report_file: &Option<String>)
+          -> Result<()> {
+    let current_dir = env::current_dir().chain_err(|| "failed to find current dir")?;
+    let repo = git::open_repo(&current_dir).chain_err(|| "failed to open git repo")?;
+    let head = repo.head()
+        .and_then(|h| h.peel_to_commit())
+        .chain_err(|| "HEAD not a commit")?;
+    let base_commit = repo.revparse_single(base)
+        .and_then(|o| o.peel_to_commit())
+        .chain_err(|| format!("invalid base `{}`", base))?;
+    let merge_base = repo.merge_base(base_commit.id(), head.id())
+        .chain_err(|| format!("HEAD has no common ancestor with `{}`", base))?;
+    if merge_base == head.id() {
+        throw!("HEAD is already part of `{}`, so there is nothing to compare", base);
+    }
+
+    let mut ids = vec![merge_base];
+    if all_commits {
+        let mut walk = repo.revwalk().chain_err(|| "failed to list commits")?;
+        walk.push(head.id()).chain_err(|| "failed to list commits")?;
+        walk.hide(merge_base).chain_err(|| "failed to list commits")?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE).chain_err(|| "failed to list commits")?;
+        for id in walk {
+            ids.push(id.chain_err(|| "failed to list commits")?);
+        }
+    } else {
+        ids.push(head.id());
+    }
+    let ids: Vec<_> = ids.iter().map(|id| id.to_string()).collect();
+    let commits = Some(ids.join(" "));
+
+    let storage = Storage::parse(config.storage)?;
+    let run_id = match bench::bench(data_file, bench::Config { commits: &commits, ..config })? {
+        Some(run_id) => run_id,
+        None => return Ok(()), // dry run
+    };
+
+    let merge_base = repo.find_commit(merge_base).chain_err(|| "failed to find the merge-base")?;
+    let measurements: Vec<_> = store::load_measurements(data_file, storage)?
+        .into_iter()
+        .filter(|m| m.run.as_ref() == Some(&run_id) && data::BENCH_METRICS.contains(&&m.metric[..]))
+        .collect();
+    if measurements.is_empty() {
+        throw!("no benchmark results to compare");
+    }
+    let report = report(&measurements,
+                        (&git::label(&merge_base), base),
+                        &git::label(&head),
+                        &noise::load_noise(data_file)?);
+
+    println!();
+    print!("{}", report);
+    if let Some(ref path) = *report_file {
+        File::create(path)
+            .and_then(|mut file| file.write_all(report.as_bytes()))
+            .chain_err(|| format!("failed to write report to `{}`", path))?;
+    }
+    Ok(())
+}
+
+/// A markdown table comparing each test's median at `base` (the label
+/// of the merge-base, and the branch) and at `head`. Changes larger
+/// than the noise of the test (if `noise` measured it) are flagged.
+fn report(measurements: &[Measurement],
+          (base_label, base): (&str, &str),
+          head_label: &str,
+          noise: &[noise::Noise])
+          -> String {
+    // (unit, values at base, values at head) of each test
+    let mut keys = vec![];
+    let mut values: HashMap<Key, (&str, Vec<f64>, Vec<f64>)> = HashMap::new();
+    for m in measurements {
+        let key = m.key().without_commit();
+        let entry = values.entry(key.clone())
+            .or_insert_with(|| {
+                keys.push(key);
+                (&m.unit, vec![], vec![])
+            });
+        if m.commit == base_label {
+            entry.1.push(m.value);
+        } else if m.commit == head_label {
+            entry.2.push(m.value);
+        }
+    }
+
+    // The latest noise level of each test.
+    let detectable: HashMap<_, _> = noise.iter().map(|n| (&n.key, n.detectable)).collect();
+
+    let mut report = String::new();
+    report.push_str(&format!("### Benchmarks: `{}` (merge-base with `{}`) vs. `{}` (HEAD)\n\n",
+                             base_label,
+                             base,
+                             head_label));
+    report.push_str("| test | metric | base | HEAD | change |\n");
+    report.push_str("|:-----|:-------|-----:|-----:|-------:|\n");
+    let mut unflagged = false;
+    for key in &keys {
+        let (unit, ref mut before, ref mut after) = *values.get_mut(key).unwrap();
+        let median = |values: &mut Vec<f64>| {
+            if values.is_empty() {
+                return None;
+            }
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            Some(plot::compute_median_and_error(values).0)
+        };
+        let (before, after) = (median(before), median(after));
+        let max = before.unwrap_or(0.0).max(after.unwrap_or(0.0));
+        let (scaled_unit, factor) = plot::scale_unit(unit, max);
+        let format = |value: Option<f64>| match value {
+            Some(v) if unit == "count" => format!("{:.0}", v),
+            Some(v) => format!("{:.2} {}", v / factor, scaled_unit),
+            None => "n/a".to_string(),
+        };
+
+        let change = match (before, after) {
+            (Some(before), Some(after)) if before != 0.0 => {
+                let change = (after - before) / before;
+                let verdict = match detectable.get(key) {
+                    Some(&threshold) if change.abs() > threshold => {
+                        let better = (change > 0.0) == data::higher_is_better(&key.metric);
+                        if better { " (improvement)" } else { " (regression)" }
+                    }
+                    Some(_) => "",
+                    None => {
+                        unflagged = true;
+                        ""
+                    }
+                };
+                format!("{:+.1}%{}", change * 100.0, verdict)
+            }
+            _ => String::new(),
+        };
+
+        report.push_str(&format!("| {} | {} | {} | {} | {} |\n",
+                                 noise::describe(key),
+                                 key.metric,
+                                 format(before),
+                                 format(after),
+                                 change));
+    }
+    if unflagged {
+        report.push_str("\nSome tests have no recorded noise level, so their changes are not \
+                         flagged; run `cargo-chrono noise` to measure it.\n");
+    }
+    report
+}
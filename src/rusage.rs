@@ -0,0 +1,83 @@
+use std::io;
+use std::process::{Command, Output, Stdio};
+
+/// Resources used by a child process, as reported by `wait4`.
+#[derive(Clone, Debug)]
+pub struct Usage {
+    pub max_rss: u64, // in bytes
+    pub user_time: u64, // in ns
+    pub system_time: u64, // in ns
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+}
+
+impl Usage {
+    /// The usage as `(metric, value)` pairs, as stored in the data file
+    /// (in the units given by `data::default_unit`).
+    pub fn metrics(&self) -> Vec<(&'static str, u64)> {
+        vec![("max-rss", self.max_rss),
+             ("user-time", self.user_time),
+             ("system-time", self.system_time),
+             ("voluntary-switches", self.voluntary_switches),
+             ("involuntary-switches", self.involuntary_switches)]
+    }
+}
+
+/// Like `command.output()`, but also returns the resources used by the
+/// child process (where the platform can tell us). The child's stderr
+/// is discarded.
+#[cfg(unix)]
+pub fn output_with_usage(command: &mut Command) -> io::Result<(Output, Option<Usage>)> {
+    use libc;
+    use std::io::Read;
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut child = command.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let mut stdout = vec![];
+    if let Some(ref mut out) = child.stdout {
+        out.read_to_end(&mut stdout)?;
+    }
+
+    // Reap the child ourselves (rather than with `child.wait()`) so
+    // that we get its resource usage.
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { ::std::mem::zeroed() };
+    loop {
+        let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut rusage) };
+        if pid >= 0 {
+            break;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+
+    // Linux reports the max RSS in KiB, macOS in bytes.
+    let rss_unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    let nanos = |t: libc::timeval| t.tv_sec as u64 * 1_000_000_000 + t.tv_usec as u64 * 1_000;
+    let usage = Usage {
+        max_rss: rusage.ru_maxrss as u64 * rss_unit,
+        user_time: nanos(rusage.ru_utime),
+        system_time: nanos(rusage.ru_stime),
+        voluntary_switches: rusage.ru_nvcsw as u64,
+        involuntary_switches: rusage.ru_nivcsw as u64,
+    };
+
+    let output = Output {
+        status: ExitStatusExt::from_raw(status),
+        stdout: stdout,
+        stderr: vec![],
+    };
+    Ok((output, Some(usage)))
+}
+
+#[cfg(not(unix))]
+pub fn output_with_usage(command: &mut Command) -> io::Result<(Output, Option<Usage>)> {
+    let output = command.stdin(Stdio::null()).stderr(Stdio::null()).output()?;
+    Ok((output, None))
+}
@@ -0,0 +1,177 @@
+use doctor;
+use errors::*;
+use std::process::Command;
+
+/// Where and at what priority the bench executables run. The builds
+/// are not affected, so that they can use every core.
+#[derive(Clone, Debug, Default)]
+pub struct Sched {
+    pub cpus: Vec<usize>, // CPUs to pin the benchmarks to (empty for any)
+    pub nice: Option<i32>, // niceness of the benchmarks, if not inherited
+}
+
+impl Sched {
+    /// Parse `--cpu-list` (like `taskset`: `4-7` or `0,2,4-6`) and
+    /// `--nice`.
+    pub fn parse(cpu_list: &Option<String>, nice: &Option<String>) -> Result<Sched> {
+        if (cpu_list.is_some() || nice.is_some()) && !cfg!(target_os = "linux") {
+            throw!("`--cpu-list` and `--nice` are only supported on Linux");
+        }
+
+        let mut sched = Sched::default();
+        if let Some(ref list) = *cpu_list {
+            let cpus = max_cpus();
+            for range in list.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+                let (first, last) = match range.find('-') {
+                    Some(i) => (&range[..i], &range[i + 1..]),
+                    None => (range, range),
+                };
+                let first: usize = first.parse()
+                    .chain_err(|| format!("invalid CPU `{}` in `{}`", first, list))?;
+                let last: usize = last.parse()
+                    .chain_err(|| format!("invalid CPU `{}` in `{}`", last, list))?;
+                if first > last {
+                    throw!("invalid CPU range `{}` in `{}`", range, list);
+                }
+                if last >= cpus {
+                    throw!("there is no CPU {} (in `{}`); the CPUs are numbered 0 to {}",
+                           last,
+                           list,
+                           cpus - 1);
+                }
+                sched.cpus.extend(first..last + 1);
+            }
+            if sched.cpus.is_empty() {
+                throw!("CPU list `{}` is empty", list);
+            }
+        }
+        if let Some(ref nice) = *nice {
+            let nice: i32 = nice.parse()
+                .chain_err(|| format!("`--nice` should be a number, not `{}`", nice))?;
+            if !(-20..=19).contains(&nice) {
+                throw!("`--nice` should be between -20 and 19, not `{}`", nice);
+            }
+            sched.nice = Some(nice);
+        }
+        Ok(sched)
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.cpus.is_empty() && self.nice.is_none()
+    }
+
+    /// The CPU list, as given to `taskset`.
+    pub fn describe_cpus(&self) -> String {
+        let cpus: Vec<_> = self.cpus.iter().map(|c| c.to_string()).collect();
+        cpus.join(",")
+    }
+
+    /// Make `command` run on our CPUs and at our priority, by setting
+    /// them in the child before it executes the benchmark.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, command: &mut Command) {
+        use libc;
+        use std::io;
+        use std::mem;
+        use std::os::unix::process::CommandExt;
+
+        if self.is_default() {
+            return;
+        }
+
+        let mut cpus: Option<libc::cpu_set_t> = None;
+        if !self.cpus.is_empty() {
+            let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+            for &cpu in &self.cpus {
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            cpus = Some(set);
+        }
+        let nice = self.nice;
+
+        // Only async-signal-safe calls are allowed between `fork` and
+        // `exec`, hence the set is built up front.
+        let setup = move || {
+            if let Some(ref set) = cpus {
+                if unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), set) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(nice) = nice {
+                if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        };
+        unsafe {
+            command.pre_exec(setup);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _command: &mut Command) {
+        // `parse` refuses anything but the default elsewhere.
+    }
+}
+
+/// How many CPUs `--cpu-list` can pick from: those online, and no more
+/// than a `cpu_set_t` holds.
+#[cfg(target_os = "linux")]
+fn max_cpus() -> usize {
+    use libc;
+    use std::cmp;
+
+    let set_size = libc::CPU_SETSIZE as usize;
+    cmp::min(doctor::online_cpus().unwrap_or(set_size), set_size)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn max_cpus() -> usize {
+    doctor::online_cpus().unwrap_or(1)
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use super::*;
+
+    fn parse(cpu_list: Option<&str>, nice: Option<&str>) -> Result<Sched> {
+        Sched::parse(&cpu_list.map(|c| c.to_string()), &nice.map(|n| n.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        assert!(parse(None, None).unwrap().is_default());
+    }
+
+    #[test]
+    fn parses_cpu_list() {
+        // (every machine has a CPU 0)
+        let sched = parse(Some("0, 0-0"), None).unwrap();
+        assert_eq!(sched.cpus, vec![0, 0]);
+        assert_eq!(sched.describe_cpus(), "0,0");
+    }
+
+    #[test]
+    fn rejects_bad_cpu_lists() {
+        assert!(parse(Some(""), None).is_err());
+        assert!(parse(Some("a"), None).is_err());
+        assert!(parse(Some("0-a"), None).is_err());
+        assert!(parse(Some("3-1"), None).is_err());
+    }
+
+    #[test]
+    fn rejects_cpus_that_do_not_exist() {
+        assert!(parse(Some("2000"), None).is_err());
+        assert!(parse(Some("0-4000000000"), None).is_err());
+    }
+
+    #[test]
+    fn parses_nice() {
+        assert_eq!(parse(None, Some("-20")).unwrap().nice, Some(-20));
+        assert_eq!(parse(None, Some("19")).unwrap().nice, Some(19));
+        assert!(parse(None, Some("20")).is_err());
+        assert!(parse(None, Some("x")).is_err());
+    }
+}
@@ -0,0 +1,472 @@
+use csv;
+use data::{self, Measurement};
+use errors::*;
+use git;
+use git2::{ErrorCode, Oid, Repository, Signature};
+use lock::Lock;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The notes ref that `notes` storage keeps measurements in.
+pub const NOTES_REF: &'static str = "refs/notes/chrono";
+
+/// Where measurements are kept.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Storage {
+    // appended to the data file
+    Csv,
+
+    // in a git note on each commit (in `NOTES_REF`), in the same format
+    // as the data file
+    Notes,
+
+    // in a file per run, in `data::runs_dir`, which is never changed
+    // once written (so that runs made on different branches merge)
+    Dir,
+}
+
+impl Storage {
+    /// Parse `--storage`.
+    pub fn parse(storage: &str) -> Result<Storage> {
+        match storage {
+            "csv" => Ok(Storage::Csv),
+            "notes" => Ok(Storage::Notes),
+            "dir" => Ok(Storage::Dir),
+            _ => throw!("`--storage` should be `csv`, `notes` or `dir`, not `{}`", storage),
+        }
+    }
+}
+
+/// Load every measurement: from the data file, from every note (oldest
+/// commit first), or from each run file in turn.
+pub fn load_measurements(data_file: &str, storage: Storage) -> Result<Vec<Measurement>> {
+    match storage {
+        Storage::Csv => data::load_measurements(data_file),
+        Storage::Dir => load_dir(&data::runs_dir(Path::new(data_file))),
+        Storage::Notes => {
+            let current_dir = env::current_dir().chain_err(|| "failed to find current dir")?;
+            let repo = git::open_repo(&current_dir).chain_err(|| "failed to open git repo")?;
+            load_notes(&repo)
+        }
+    }
+}
+
+/// Whether there are any measurements to load.
+pub fn exists(data_file: &str, storage: Storage) -> bool {
+    match storage {
+        Storage::Csv => Path::new(data_file).exists(),
+        Storage::Dir => data::runs_dir(Path::new(data_file)).exists(),
+        Storage::Notes => {
+            env::current_dir()
+                .ok()
+                .and_then(|dir| git::open_repo(&dir).ok())
+                .is_some_and(|repo| repo.find_reference(NOTES_REF).is_ok())
+        }
+    }
+}
+
+/// Every note is loaded, not just those on the current branch, so that
+/// results for other branches (or for commits since rebased away) are
+/// not lost. They are ordered by the time of their commit (those whose
+/// commit is gone come last).
+fn load_notes(repo: &Repository) -> Result<Vec<Measurement>> {
+    if repo.find_reference(NOTES_REF).is_err() {
+        throw!("no measurements are stored in git notes (`{}`)", NOTES_REF);
+    }
+
+    let notes = repo.notes(Some(NOTES_REF)).chain_err(|| "failed to list the notes")?;
+    let mut ids = vec![];
+    for ids_of_note in notes {
+        let (_, id) = ids_of_note.chain_err(|| "failed to list the notes")?;
+        let time = repo.find_commit(id).ok().map(|c| c.time().seconds());
+        ids.push((time.is_none(), time, id));
+    }
+    ids.sort();
+
+    let mut measurements = vec![];
+    for (_, _, id) in ids {
+        let note = repo.find_note(Some(NOTES_REF), id)
+            .chain_err(|| format!("failed to read the note of `{}`", id))?;
+        let text = match note.message() {
+            Some(text) => text,
+            None => throw!("note of `{}` not utf-8", id),
+        };
+        measurements.extend(data::parse_measurements(text)
+            .chain_err(|| format!("failed to decode the note of `{}`", id))?);
+    }
+    Ok(measurements)
+}
+
+/// The run files are named after the run, so they sort by time. The
+/// same run may have been merged in twice (under different names), so
+/// duplicates are skipped.
+fn load_dir(dir: &Path) -> Result<Vec<Measurement>> {
+    let entries = fs::read_dir(dir).chain_err(|| format!("cannot read `{}`", dir.display()))?;
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry.chain_err(|| format!("cannot read `{}`", dir.display()))?.path();
+        if path.extension() == Some(OsStr::new("csv")) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut runs = HashSet::new();
+    let mut measurements = vec![];
+    for path in paths {
+        let (run, run_measurements) = data::load_run(&path)?;
+        if let Some(run) = run {
+            if !runs.insert(run.clone()) {
+                println_err!("warning: skipping `{}`, a duplicate of run `{}`", path.display(), run);
+                continue;
+            }
+        }
+        measurements.extend(run_measurements);
+    }
+    Ok(measurements)
+}
+
+/// Where `bench` writes its measurements (with `data::write_measurement`).
+/// Nothing is stored until it is flushed.
+pub enum Output<'r> {
+    // the rows written since the last flush, which are then appended to
+    // the file all at once
+    File {
+        file: File,
+        path: PathBuf,
+        buffer: Vec<u8>,
+    },
+
+    // the run file being written, which is moved into place by `finish`
+    // (so that an interrupted run leaves no partial run file behind)
+    Dir {
+        file: File,
+        temp_path: PathBuf,
+        path: PathBuf,
+        empty: bool,
+    },
+
+    // the rows written so far, which go to the notes when flushed
+    Notes {
+        repo: &'r Repository,
+        commits: HashMap<String, Oid>, // the commit each label's note goes on
+        buffer: Vec<u8>,
+    },
+}
+
+impl<'r> Output<'r> {
+    /// Open the output for run `run_id` of `bench`. For `notes`,
+    /// `commits` gives the commit to attach the measurements of each
+    /// commit label to.
+    pub fn open(data_path: &Path,
+                storage: Storage,
+                run_id: &str,
+                repo: &'r Repository,
+                commits: HashMap<String, Oid>)
+                -> Result<Output<'r>> {
+        match storage {
+            Storage::Csv => {
+                Output::append(data_path)
+                    .chain_err(|| format!("failed to open data file `{}`", data_path.display()))
+            }
+            Storage::Notes => {
+                Ok(Output::Notes {
+                    repo: repo,
+                    commits: commits,
+                    buffer: vec![],
+                })
+            }
+            Storage::Dir => {
+                let dir = data::runs_dir(data_path);
+                fs::create_dir_all(&dir)
+                    .chain_err(|| format!("failed to create `{}`", dir.display()))?;
+                let path = dir.join(format!("{}.csv", run_id));
+                let temp_path = path.with_extension("csv.tmp");
+                let file = File::create(&temp_path)
+                    .chain_err(|| format!("failed to create `{}`", temp_path.display()))?;
+                Ok(Output::Dir {
+                    file: file,
+                    temp_path: temp_path,
+                    path: path,
+                    empty: true,
+                })
+            }
+        }
+    }
+
+    /// Append to the CSV file at `path` (which is created if need be).
+    pub fn append(path: &Path) -> Result<Output<'r>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .chain_err(|| format!("failed to open `{}`", path.display()))?;
+        Ok(Output::File {
+            file: file,
+            path: path.to_owned(),
+            buffer: vec![],
+        })
+    }
+
+    /// Done writing: move the run file into place (if anything was
+    /// written to it).
+    pub fn finish(self) -> Result<()> {
+        if let Output::Dir { file, temp_path, path, empty } = self {
+            drop(file);
+            if empty {
+                fs::remove_file(&temp_path)
+                    .chain_err(|| format!("failed to remove `{}`", temp_path.display()))?;
+            } else {
+                fs::rename(&temp_path, &path)
+                    .chain_err(|| format!("failed to write `{}`", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'r> Write for Output<'r> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Output::File { ref mut buffer, .. } |
+            Output::Notes { ref mut buffer, .. } => {
+                buffer.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            Output::Dir { ref mut file, ref mut empty, .. } => {
+                *empty &= buf.is_empty();
+                file.write(buf)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Output::File { ref file, ref path, ref mut buffer } => {
+                if buffer.is_empty() {
+                    return Ok(());
+                }
+                append(file, path, buffer).map_err(|e| io::Error::other(e.to_string()))?;
+                buffer.clear();
+                Ok(())
+            }
+            Output::Dir { ref mut file, .. } => file.flush(),
+            Output::Notes { repo, ref commits, ref mut buffer } => {
+                let text = String::from_utf8_lossy(buffer).into_owned();
+                buffer.clear();
+                append_notes(repo, commits, &text)
+                    .map_err(|e| io::Error::other(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Append `rows` to `file` in a single write, holding its lock (so that
+/// the rows of concurrent sessions do not interleave), and make sure
+/// they are on disk. A partial record left at the end of the file by a
+/// session that crashed is removed first, so that it does not run into
+/// ours.
+fn append(mut file: &File, path: &Path, rows: &[u8]) -> Result<()> {
+    let _lock = Lock::exclusive(file).chain_err(|| format!("failed to lock `{}`", path.display()))?;
+    let complete = complete_len(file).chain_err(|| format!("cannot read `{}`", path.display()))?;
+    let len = file.metadata().chain_err(|| format!("cannot read `{}`", path.display()))?.len();
+    if complete < len {
+        let mut partial = vec![0; (len - complete) as usize];
+        file.seek(SeekFrom::Start(complete))
+            .and_then(|_| file.read_exact(&mut partial))
+            .and_then(|()| file.set_len(complete))
+            .chain_err(|| format!("failed to remove partial record from `{}`", path.display()))?;
+        println_err!("warning: removed partial record from the end of `{}`: `{}`",
+                     path.display(),
+                     String::from_utf8_lossy(&partial));
+    }
+    file.write_all(rows)
+        .and_then(|()| file.sync_data())
+        .chain_err(|| format!("failed to write to `{}`", path.display()))
+}
+
+/// The length of `file` up to the end of its last complete record
+/// (i.e., its last newline).
+fn complete_len(mut file: &File) -> io::Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut chunk = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let chunk = &mut chunk[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(i) = chunk.iter().rposition(|&b| b == b'\n') {
+            return Ok(start + i as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Append the measurements in `text` to the notes of their commits.
+fn append_notes(repo: &Repository, commits: &HashMap<String, Oid>, text: &str) -> Result<()> {
+    let mut notes: Vec<(Oid, String)> = vec![];
+    for m in data::parse_measurements(text)? {
+        let id = match commits.get(&m.commit) {
+            Some(&id) => id,
+            None => throw!("bug: no commit to attach the results of `{}` to", m.commit),
+        };
+        let mut row = csv::Writer::from_memory();
+        data::write_measurement(&mut row, &m)?;
+        match notes.iter().position(|&(note_id, _)| note_id == id) {
+            Some(i) => notes[i].1.push_str(row.as_string()),
+            None => notes.push((id, row.as_string().to_string())),
+        }
+    }
+
+    let signature = repo.signature()
+        .or_else(|_| Signature::now("cargo-chrono", "cargo-chrono"))
+        .chain_err(|| "failed to create a signature")?;
+    for (id, rows) in notes {
+        let mut text = match repo.find_note(Some(NOTES_REF), id) {
+            Ok(note) => note.message().unwrap_or_default().to_string(),
+            Err(ref e) if e.code() == ErrorCode::NotFound => String::new(),
+            Err(e) => return Err(e).chain_err(|| format!("failed to read the note of `{}`", id)),
+        };
+        text.push_str(&rows);
+        repo.note(&signature, &signature, Some(NOTES_REF), id, &text, true)
+            .chain_err(|| format!("failed to write the note of `{}`", id))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Time;
+    use std::process;
+
+    /// A file of its own for each test, holding `contents`.
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("cargo-chrono-{}-{}.csv", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn complete_len_stops_at_the_last_newline() {
+        let path = temp_file("complete-len", b"a,1\nb,2\nc,");
+        assert_eq!(complete_len(&File::open(&path).unwrap()).unwrap(), 8);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn complete_len_without_any_newline() {
+        let path = temp_file("no-newline", b"a,1");
+        assert_eq!(complete_len(&File::open(&path).unwrap()).unwrap(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn append_removes_a_partial_record() {
+        let path = temp_file("append", b"a,1\nb,");
+        let file = OpenOptions::new().read(true).append(true).open(&path).unwrap();
+        append(&file, &path, b"c,3\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a,1\nc,3\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn append_to_a_file_without_any_newline() {
+        let path = temp_file("append-no-newline", b"a,");
+        let file = OpenOptions::new().read(true).append(true).open(&path).unwrap();
+        append(&file, &path, b"c,3\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"c,3\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// A repository of its own for each test, with no commits.
+    fn temp_repo(name: &str) -> (PathBuf, Repository) {
+        let dir = env::temp_dir().join(format!("cargo-chrono-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        (dir, repo)
+    }
+
+    /// A commit made at `seconds`, on `HEAD` or on no branch at all.
+    fn commit_at(repo: &Repository, head: bool, seconds: i64) -> Oid {
+        let signature = Signature::new("test", "test@example.com", &Time::new(seconds, 0)).unwrap();
+        let tree_id = repo.treebuilder(None).unwrap().write().unwrap();
+        let tree = repo.find_tree(tree_id).unwrap();
+        let update_ref = if head { Some("HEAD") } else { None };
+        repo.commit(update_ref, &signature, &signature, "test", &tree, &[]).unwrap()
+    }
+
+    fn rows(measurements: &[Measurement]) -> String {
+        let mut writer = csv::Writer::from_memory();
+        for m in measurements {
+            data::write_measurement(&mut writer, m).unwrap();
+        }
+        writer.as_string().to_string()
+    }
+
+    #[test]
+    fn no_notes() {
+        let (dir, repo) = temp_repo("no-notes");
+        assert!(load_notes(&repo).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn notes_in_order_of_their_commits() {
+        let (dir, repo) = temp_repo("notes");
+        let mut commits = HashMap::new();
+        commits.insert("new".to_string(), commit_at(&repo, true, 2000));
+        commits.insert("old".to_string(), commit_at(&repo, false, 1000));
+        commits.insert("gone".to_string(), repo.blob(b"not a commit").unwrap());
+
+        let text = rows(&[Measurement::new("gone", "a", "time", 1.0),
+                          Measurement::new("new", "a", "time", 2.0),
+                          Measurement::new("old", "a", "time", 3.0)]);
+        append_notes(&repo, &commits, &text).unwrap();
+        append_notes(&repo, &commits, &rows(&[Measurement::new("new", "b", "time", 4.0)]))
+            .unwrap();
+
+        let loaded: Vec<_> = load_notes(&repo)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.commit, m.test, m.value))
+            .collect();
+        assert_eq!(loaded,
+                   vec![("old".to_string(), "a".to_string(), 3.0),
+                        ("new".to_string(), "a".to_string(), 2.0),
+                        ("new".to_string(), "b".to_string(), 4.0),
+                        ("gone".to_string(), "a".to_string(), 1.0)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn notes_need_a_commit() {
+        let (dir, repo) = temp_repo("notes-no-commit");
+        let text = rows(&[Measurement::new("abc", "a", "time", 1.0)]);
+        assert!(append_notes(&repo, &HashMap::new(), &text).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_dir_skips_duplicate_runs() {
+        let dir = env::temp_dir().join(format!("cargo-chrono-runs-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let run = "run-1,args,bench\nabc,a,1,,,run-1\n";
+        fs::write(dir.join("20240101-120000-1.csv"), run).unwrap();
+        fs::write(dir.join("20240101-120000-1 (copy).csv"), run).unwrap();
+        fs::write(dir.join("20240102-120000-2.csv"), "run-2,args,bench\nabc,a,2,,,run-2\n")
+            .unwrap();
+        fs::write(dir.join("notes.txt"), "not a run file").unwrap();
+
+        let values: Vec<_> = load_dir(&dir).unwrap().into_iter().map(|m| m.value).collect();
+        assert_eq!(values, vec![1.0, 2.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use bench_args;
+use errors::*;
+
+/// One way of building and running the benchmarks. Each commit is
+/// measured under every variant that the user asks for.
+#[derive(Clone, Debug, Default)]
+pub struct Variant {
+    pub name: String, // recorded with each measurement ("" if no variants were given)
+    pub toolchain: Option<String>, // e.g. `nightly`, for `cargo +nightly`
+    pub cargo_flags: Vec<String>, // in addition to those given to `bench`
+    pub env: Vec<(String, String)>, // e.g. `RUSTFLAGS`, for building and running
+}
+
+impl Variant {
+    /// Parse a variant from a spec like `simd: +nightly --features simd
+    /// RUSTFLAGS=-Ctarget-cpu=native`. The `name:` prefix is optional;
+    /// without it, the variant is named after the whole spec.
+    pub fn parse(spec: &str) -> Result<Variant> {
+        let (name, items) = match spec.find(':') {
+            Some(i) if !spec[..i].contains(char::is_whitespace) && !spec[..i].contains('=') => {
+                (spec[..i].trim(), &spec[i + 1..])
+            }
+            _ => (spec.trim(), spec),
+        };
+        if name.is_empty() {
+            throw!("variant `{}` has no name", spec);
+        }
+
+        let mut variant = Variant {
+            name: name.to_string(),
+            ..Variant::default()
+        };
+        let mut items = items.split_whitespace();
+        while let Some(item) = items.next() {
+            if let Some(toolchain) = item.strip_prefix('+') {
+                if variant.toolchain.is_some() {
+                    throw!("variant `{}` names more than one toolchain", name);
+                }
+                variant.toolchain = Some(toolchain.to_string());
+            } else if !item.starts_with("-") && item.contains('=') {
+                let i = item.find('=').unwrap();
+                variant.env.push((item[..i].to_string(), item[i + 1..].to_string()));
+            } else {
+                variant.cargo_flags.push(item.to_string());
+                // (the value of a flag, like `--config KEY=VALUE`, is not
+                // an environment variable)
+                if bench_args::takes_value(item) {
+                    match items.next() {
+                        Some(value) => variant.cargo_flags.push(value.to_string()),
+                        None => throw!("cargo flag `{}` in variant `{}` needs a value", item, name),
+                    }
+                }
+            }
+        }
+        Ok(variant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_toolchain_flags_and_env() {
+        let variant = Variant::parse("simd: +nightly --features simd RUSTFLAGS=-Ctarget-cpu=native")
+            .unwrap();
+        assert_eq!(variant.name, "simd");
+        assert_eq!(variant.toolchain, Some("nightly".to_string()));
+        assert_eq!(variant.cargo_flags, vec!["--features", "simd"]);
+        assert_eq!(variant.env, env(&[("RUSTFLAGS", "-Ctarget-cpu=native")]));
+    }
+
+    #[test]
+    fn named_after_the_spec_without_a_name() {
+        let variant = Variant::parse("+stable").unwrap();
+        assert_eq!(variant.name, "+stable");
+        assert_eq!(variant.toolchain, Some("stable".to_string()));
+
+        // (a colon in an environment variable is not a name)
+        let variant = Variant::parse("PATH=/a:/b").unwrap();
+        assert_eq!(variant.name, "PATH=/a:/b");
+        assert_eq!(variant.env, env(&[("PATH", "/a:/b")]));
+    }
+
+    #[test]
+    fn value_of_a_flag_is_not_env() {
+        let variant = Variant::parse("lto: --config profile.bench.lto=true CC=clang").unwrap();
+        assert_eq!(variant.cargo_flags, vec!["--config", "profile.bench.lto=true"]);
+        assert_eq!(variant.env, env(&[("CC", "clang")]));
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        assert!(Variant::parse(": +nightly").is_err());
+        assert!(Variant::parse("two: +stable +nightly").is_err());
+        assert!(Variant::parse("cfg: --config").is_err());
+    }
+}